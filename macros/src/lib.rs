@@ -0,0 +1,95 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, FnArg, Ident, ItemFn, LitStr};
+
+// `#[get("/users/:id")]` on `fn name(request: &Request) -> Response` turns
+// `name` into a unit struct implementing Handler and Route, so it can be
+// passed to ApiEndPointManagerBuilder::service.
+#[proc_macro_attribute]
+pub fn get(args: TokenStream, item: TokenStream) -> TokenStream {
+    route("GET", args, item)
+}
+
+#[proc_macro_attribute]
+pub fn post(args: TokenStream, item: TokenStream) -> TokenStream {
+    route("POST", args, item)
+}
+
+#[proc_macro_attribute]
+pub fn put(args: TokenStream, item: TokenStream) -> TokenStream {
+    route("PUT", args, item)
+}
+
+#[proc_macro_attribute]
+pub fn delete(args: TokenStream, item: TokenStream) -> TokenStream {
+    route("DELETE", args, item)
+}
+
+fn route(method: &str, args: TokenStream, item: TokenStream) -> TokenStream {
+    let path = parse_macro_input!(args as LitStr);
+    let function = parse_macro_input!(item as ItemFn);
+    match expand(method, &path, function) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(method: &str, path: &LitStr, function: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+    // The rest of the pattern is checked when the route is registered
+    if !path.value().starts_with('/') {
+        return Err(syn::Error::new(path.span(), "route pattern must start with `/`"));
+    }
+
+    let signature = &function.sig;
+    if signature.asyncness.is_some() || !signature.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            signature,
+            "route handlers can't be async or generic",
+        ));
+    }
+    if signature.inputs.len() != 1 || !matches!(signature.inputs[0], FnArg::Typed(_)) {
+        return Err(syn::Error::new_spanned(
+            &signature.inputs,
+            "route handlers take the request as their only argument",
+        ));
+    }
+
+    let name = &signature.ident;
+    let vis = &function.vis;
+    // Documentation moves to the struct, everything else stays on the function
+    let (docs, attrs): (Vec<_>, Vec<_>) = function
+        .attrs
+        .iter()
+        .partition(|attr| attr.path().is_ident("doc"));
+    let block = &function.block;
+    let method = Ident::new(method, Span::call_site());
+
+    Ok(quote! {
+        #(#docs)*
+        #[allow(non_camel_case_types)]
+        #vis struct #name;
+
+        impl ::web_server::webserver::handler::Handler for #name {
+            fn call(
+                &self,
+                request: &::web_server::webserver::request::Request,
+            ) -> ::web_server::webserver::response::Response {
+                #(#attrs)*
+                #signature #block
+
+                #name(request)
+            }
+        }
+
+        impl ::web_server::webserver::handler::Route for #name {
+            fn method(&self) -> ::web_server::webserver::request::HttpMethod {
+                ::web_server::webserver::request::HttpMethod::#method
+            }
+
+            fn pattern(&self) -> &'static str {
+                #path
+            }
+        }
+    })
+}
@@ -0,0 +1,4 @@
+// Lets code generated by the route attributes name this crate from inside it
+extern crate self as web_server;
+
+pub mod webserver;
@@ -1,32 +1,180 @@
-use std::fs;
+use std::{
+    env, process,
+    sync::{atomic::AtomicUsize, Arc},
+    time::Instant,
+};
 
-use toml::Table;
+use web_server::webserver::{
+    api_endpoint::example_endpoint::{count_stream, hits, long_time, mirror_request, user_post, Hits},
+    api_endpoint_manager::{ApiEndPointManager, ApiEndPointManagerBuilder, RouteGroup},
+    config::{self, ServerConfig, ServerType, DEFAULT_CONFIG_PATH},
+    run,
+    middleware::{AccessLog, SetHeader},
+    request::{HttpMethod, Request},
+    response::{Response, StatusCode},
+};
 
-mod webserver;
+const USAGE: &str = "\
+Usage: web-server [check-config | routes] [--config <path>] [--<key> <value>]...
+
+Commands:
+    check-config          Validate the configuration and exit
+    routes                Print the route table and exit
+
+Options:
+    --config <path>       Config file, server-config.toml by default
+    --<key> <value>       Override a config key, e.g. --listen [::]:8081 or
+                          --keep_alive.timeout=10
+    -h, --help            Print this help
+
+Config keys can also be set with WEBSERVER_<KEY> environment variables,
+sections separated by a double underscore: WEBSERVER_KEEP_ALIVE__TIMEOUT=10.
+Command-line overrides win over the environment.";
+
+enum Command {
+    Serve,
+    CheckConfig,
+    Routes,
+}
+
+struct Args {
+    command: Command,
+    config_path: String,
+    overrides: Vec<(String, String)>,
+}
 
 fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+
+    // Environment first so the command line wins
+    let mut overrides = config::env_overrides(env::vars());
+    overrides.extend(args.overrides);
+
+    let config = match ServerConfig::load(&args.config_path, &overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    match args.command {
+        Command::Serve => {}
+        Command::CheckConfig => {
+            println!("{}: configuration OK", args.config_path);
+            return;
+        }
+        Command::Routes => {
+            let manager = build_manager(&config);
+            for warning in manager.route_warnings() {
+                eprintln!("warning: {}", warning);
+            }
+            println!("{}", manager.route_table());
+            return;
+        }
+    }
+
     init();
-    let config_str = fs::read_to_string("server-config.toml").expect("Config file doesn't exist");
-    let config: Table = toml::from_str(&config_str).expect("Config file not structured as toml");
-
-    let ip = config
-        .get("ip")
-        .and_then(|v| v.as_str())
-        .unwrap_or("localhost");
-
-    let port = config
-        .get("ip")
-        .and_then(|v| v.as_integer())
-        .unwrap_or(8080) as u16;
-    
-    let server_type = config.get("server_type").and_then(|v| v.as_str()).unwrap_or("single");
-
-    match server_type {
-        "single" => webserver::run::run_ipv4_server(ip, port),
-        "multi" => webserver::run::run_ipv4_server_multithreaded(ip, port),
-        "event" => webserver::run::run_ipv4_server_event_based(ip, port),
-        _ => panic!("No available server type"),
+    let manager = build_manager(&config);
+    match config.server_type {
+        ServerType::Single => run::run_server(&config, manager),
+        ServerType::Multi => run::run_server_multithreaded(&config, manager),
+        ServerType::Event => run::run_server_event_based(&config, manager),
+    }
+}
+
+fn build_manager(config: &ServerConfig) -> ApiEndPointManager {
+    match routes().build(config) {
+        Ok(manager) => manager,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Register Endpoints in this function
+fn routes() -> ApiEndPointManagerBuilder {
+    let started = Instant::now();
+    let uptime = move |_request: &Request| {
+        let body = format!("up for {} s\n", started.elapsed().as_secs());
+        Response::builder()
+            .header("Content-Type", "text/plain")
+            .status_code(StatusCode::OK)
+            .body(body.into())
+            .build()
+            .unwrap_or_default()
+    };
+    let no_store = SetHeader {
+        name: "Cache-Control",
+        value: "no-store",
+    };
+    let users = RouteGroup::new()
+        .wrap(SetHeader {
+            name: "Cache-Control",
+            value: "private",
+        })
+        .service(user_post);
+
+    ApiEndPointManager::builder()
+        .manage(Arc::new(Hits(AtomicUsize::new(0))))
+        .wrap(AccessLog)
+        .service(long_time)
+        .service(mirror_request)
+        .service(count_stream)
+        .mount("/users/:id", users)
+        .service_with(hits, vec![Arc::new(no_store)])
+        .route(HttpMethod::GET, "/uptime", uptime)
+        .static_files("/resource/*")
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        command: Command::Serve,
+        config_path: String::from(DEFAULT_CONFIG_PATH),
+        overrides: Vec::new(),
+    };
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            println!("{}", USAGE);
+            process::exit(0);
+        }
+        if arg == "check-config" {
+            parsed.command = Command::CheckConfig;
+            continue;
+        }
+        if arg == "routes" {
+            parsed.command = Command::Routes;
+            continue;
+        }
+
+        let option = arg
+            .strip_prefix("--")
+            .ok_or_else(|| format!("Unexpected argument `{}`", arg))?;
+        let (key, value) = match option.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("Missing value for --{}", option))?;
+                (option.to_string(), value)
+            }
+        };
+
+        if key == "config" {
+            parsed.config_path = value;
+        } else {
+            parsed.overrides.push((key, value));
+        }
     }
+    Ok(parsed)
 }
 
 fn init() {
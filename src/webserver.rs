@@ -1,6 +1,28 @@
 pub mod run;
-mod request;
-mod response;
-mod api_endpoint_manager;
+pub mod config;
+pub mod request;
+pub mod response;
+pub mod api_endpoint_manager;
+pub mod handler;
+pub mod middleware;
+pub mod path_pattern;
+mod router;
 mod http_utils;
-mod api_endpoint;
+pub mod api_endpoint;
+mod event_loop;
+mod chunked;
+mod shutdown;
+mod static_files;
+mod mime;
+mod range;
+mod conditional;
+mod compression;
+pub mod route_table;
+mod directory_listing;
+mod json;
+mod listener;
+mod stream;
+#[cfg(feature = "tls")]
+mod tls;
+
+pub use web_server_macros::{delete, get, post, put};
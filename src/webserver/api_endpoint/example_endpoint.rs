@@ -1,7 +1,12 @@
-use std::{thread::sleep, time::Duration};
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread::sleep,
+    time::Duration,
+};
 
-use crate::webserver::{request::Request, response::{Response, StatusCode}};
+use crate::webserver::{get, request::Request, response::{Response, StatusCode}};
 
+#[get("/mirror")]
 pub fn mirror_request(request: &Request) -> Response {
     
     let mut response = Response::builder();
@@ -12,6 +17,7 @@ pub fn mirror_request(request: &Request) -> Response {
     response.build().unwrap_or_default()
 }
 
+#[get("/long_time")]
 pub fn long_time(_request: &Request) -> Response {
     sleep(Duration::from_millis(500));
     let body = "You have sleeped for 500 ms";
@@ -22,3 +28,59 @@ pub fn long_time(_request: &Request) -> Response {
         .build()
         .unwrap_or_default()
 }
+
+// Streams `?n=` lines, 1000 by default
+#[get("/count")]
+pub fn count_stream(request: &Request) -> Response {
+    let count = request
+        .query
+        .get("n")
+        .and_then(|n| n.parse::<usize>().ok())
+        .unwrap_or(1000)
+        .min(1_000_000);
+    let lines = (1..=count).map(|i| format!("line {}\n", i).into_bytes());
+    Response::builder()
+        .header("Content-Type", "text/plain")
+        .status_code(StatusCode::OK)
+        .chunks(lines)
+        .build()
+        .unwrap_or_default()
+}
+
+// Mounted under /users/:id
+#[get("/posts/:post_id")]
+pub fn user_post(request: &Request) -> Response {
+    let body = format!(
+        "user {}, post {}\n",
+        request.param("id").unwrap_or_default(),
+        request.param("post_id").unwrap_or_default()
+    );
+    Response::builder()
+        .header("Content-Type", "text/plain")
+        .status_code(StatusCode::OK)
+        .body(body.into())
+        .build()
+        .unwrap_or_default()
+}
+
+// Requests served by /hits, kept in the app state
+pub struct Hits(pub AtomicUsize);
+
+#[get("/hits")]
+pub fn hits(request: &Request) -> Response {
+    let count = match request.state::<Hits>() {
+        Some(hits) => hits.0.fetch_add(1, Ordering::Relaxed) + 1,
+        None => {
+            return Response::builder()
+                .status_code(StatusCode::InternalServerError)
+                .build()
+                .unwrap_or_default()
+        }
+    };
+    Response::builder()
+        .header("Content-Type", "text/plain")
+        .status_code(StatusCode::OK)
+        .body(format!("{}\n", count).into())
+        .build()
+        .unwrap_or_default()
+}
@@ -1,158 +1,536 @@
-use std::{fs::File, io::Read, path::PathBuf};
+use std::sync::Arc;
 
-use log::{debug, error, info, trace};
+use log::{debug, info, trace, warn};
 
 use crate::webserver::response::{Response, StatusCode};
 
 use super::{
-    api_endpoint::example_endpoint::{long_time, mirror_request},
-    request::{HttpMethod, Request},
+    compression::Compression,
+    config::ServerConfig,
+    handler::{AppState, Handler, Route},
+    middleware::{run_layers, Middleware},
+    path_pattern::{PathPattern, RouteError},
+    request::{normalize_path, HttpMethod, Params, Request},
+    route_table::{RouteInfo, RouteTable},
+    router::Router,
+    static_files::StaticFiles,
 };
 
-impl ApiEndPointManager {
-    // Register Endpoints in this function
-    fn register_endpoints(&mut self) {
-        info!("Registering Api Endpoints...");
+enum ApiType {
+    Static,
+    Function(Box<dyn Handler>),
+    // The route table as JSON
+    RouteTable,
+}
 
-        self.register(
-            vec![HttpMethod::GET],
-            "/long_time",
-            ApiType::Function(long_time),
-        );
-        self.register(
-            vec![HttpMethod::GET],
-            "/mirror",
-            ApiType::Function(mirror_request),
-        );
-        self.register(vec![HttpMethod::GET], "/resource/*", ApiType::Static);
+impl ApiType {
+    fn function(handler: impl Handler + 'static) -> Self {
+        ApiType::Function(Box::new(handler))
     }
 }
 
-#[allow(dead_code)]
-enum ApiType {
-    Static,
-    Function(fn(&Request) -> Response),
+struct ApiEndPoint {
+    method: Vec<HttpMethod>,
+    pattern: PathPattern,
+    api_type: ApiType,
+    // Runs inside the global middleware, around api_type only
+    middleware: Vec<Arc<dyn Middleware>>,
 }
 
-struct ApiEndPoint {
+impl ApiEndPoint {
+    fn contain_method(&self, method: &HttpMethod) -> bool {
+        self.method.contains(method)
+    }
+}
+
+// Endpoint of routes serving method. HEAD falls back to a GET endpoint when
+// none is registered for HEAD itself.
+fn find_endpoint<'a>(
+    mut endpoints: impl Iterator<Item = &'a ApiEndPoint> + Clone,
+    method: &HttpMethod,
+) -> Option<&'a ApiEndPoint> {
+    let mut fallback = endpoints.clone();
+    endpoints.find(|endpoint| endpoint.contain_method(method)).or_else(|| match method {
+        HttpMethod::HEAD => fallback.find(|endpoint| endpoint.contain_method(&HttpMethod::GET)),
+        _ => None,
+    })
+}
+
+pub struct ApiEndPointManager {
+    endpoints: Vec<ApiEndPoint>,
+    // Indices into endpoints
+    router: Router<usize>,
+    state: Arc<AppState>,
+    // Wraps every request, including those matching no endpoint
+    middleware: Vec<Arc<dyn Middleware>>,
+    static_files: StaticFiles,
+    compression: Compression,
+    // Whether TRACE is echoed back
+    trace: bool,
+}
+
+// Collects the routes of an application, they are checked when the manager
+// is built
+pub struct ApiEndPointManagerBuilder {
+    routes: RouteGroup,
+    state: AppState,
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+// Routes built apart from the manager, to be mounted under a prefix. The
+// group's middleware runs around each of its routes, outside the middleware
+// of the route itself.
+pub struct RouteGroup {
+    routes: Vec<PendingRoute>,
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+struct PendingRoute {
     method: Vec<HttpMethod>,
     path: String,
     api_type: ApiType,
+    middleware: Vec<Arc<dyn Middleware>>,
 }
 
-impl ApiEndPoint {
-    fn path_matches(&self, other: &str) -> bool {
-        let mut pattern = self.path.chars();
-        let mut other = other.chars();
-
-
-        loop {
-            let pc = pattern.next();
-            let oc = other.next();
-            match (pc, oc) {
-                (Some(pc), Some(oc)) => {
-                    if pc == '*' {
-                        break;
-                    }
-                    if pc != oc {
-                        return false;
-                    }
-                }
-                (None, None) => break,
-                _ => return false,
-            }
+impl RouteGroup {
+    pub fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            middleware: Vec::new(),
         }
+    }
 
-        true
+    pub fn route(self, method: HttpMethod, path: &str, handler: impl Handler + 'static) -> Self {
+        self.route_with(method, path, handler, Vec::new())
     }
 
-    fn contain_method(&self, method: &HttpMethod) -> bool {
-        if self.method.contains(&method) {
-            true
-        } else {
-            false
+    // Like route, with middleware running around this handler only
+    pub fn route_with(
+        mut self,
+        method: HttpMethod,
+        path: &str,
+        handler: impl Handler + 'static,
+        middleware: Vec<Arc<dyn Middleware>>,
+    ) -> Self {
+        self.routes.push(PendingRoute {
+            method: vec![method],
+            path: path.to_string(),
+            api_type: ApiType::function(handler),
+            middleware,
+        });
+        self
+    }
+
+    // A handler declared with #[get], #[post], ...
+    pub fn service(self, route: impl Route + 'static) -> Self {
+        self.service_with(route, Vec::new())
+    }
+
+    pub fn service_with(
+        self,
+        route: impl Route + 'static,
+        middleware: Vec<Arc<dyn Middleware>>,
+    ) -> Self {
+        let method = route.method();
+        let path = route.pattern();
+        self.route_with(method, path, route, middleware)
+    }
+
+    // Serves GET requests for path from the static_files root, path usually
+    // ends with a wildcard. Then the prefix before it is served too, so
+    // `/resource` gets redirected to `/resource/` like any directory.
+    pub fn static_files(mut self, path: &str) -> Self {
+        let prefix = path
+            .rsplit_once('/')
+            .filter(|(prefix, last)| last.starts_with('*') && !prefix.is_empty())
+            .map(|(prefix, _)| prefix.to_string());
+        for path in std::iter::once(path.to_string()).chain(prefix) {
+            self.routes.push(PendingRoute {
+                method: vec![HttpMethod::GET],
+                path,
+                api_type: ApiType::Static,
+                middleware: Vec::new(),
+            });
+        }
+        self
+    }
+
+    // Adds middleware around every route of the group, wherever it was
+    // added. The first one added is the outermost layer.
+    pub fn wrap(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    // Adds the routes of group with prefix put in front of their paths, so
+    // `/users` mounted at `/api/v1` serves `/api/v1/users`. The prefix may
+    // hold parameters but no wildcard.
+    pub fn mount(mut self, prefix: &str, group: RouteGroup) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        for route in group.routes {
+            let mut middleware = group.middleware.clone();
+            middleware.extend(route.middleware);
+            self.routes.push(PendingRoute {
+                path: format!("{}{}", prefix, route.path),
+                middleware,
+                ..route
+            });
         }
+        self
     }
 }
 
-pub struct ApiEndPointManager {
-    endpoints: Vec<ApiEndPoint>,
+impl Default for RouteGroup {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl ApiEndPointManager {
-    pub fn get() -> ApiEndPointManager {
-        let mut manager = Self {
+impl ApiEndPointManagerBuilder {
+    pub fn route(mut self, method: HttpMethod, path: &str, handler: impl Handler + 'static) -> Self {
+        self.routes = self.routes.route(method, path, handler);
+        self
+    }
+
+    pub fn route_with(
+        mut self,
+        method: HttpMethod,
+        path: &str,
+        handler: impl Handler + 'static,
+        middleware: Vec<Arc<dyn Middleware>>,
+    ) -> Self {
+        self.routes = self.routes.route_with(method, path, handler, middleware);
+        self
+    }
+
+    pub fn service(mut self, route: impl Route + 'static) -> Self {
+        self.routes = self.routes.service(route);
+        self
+    }
+
+    pub fn service_with(
+        mut self,
+        route: impl Route + 'static,
+        middleware: Vec<Arc<dyn Middleware>>,
+    ) -> Self {
+        self.routes = self.routes.service_with(route, middleware);
+        self
+    }
+
+    pub fn static_files(mut self, path: &str) -> Self {
+        self.routes = self.routes.static_files(path);
+        self
+    }
+
+    pub fn mount(mut self, prefix: &str, group: RouteGroup) -> Self {
+        self.routes = self.routes.mount(prefix, group);
+        self
+    }
+
+    // Makes value available to every handler through Request::state
+    pub fn manage<T: Send + Sync + 'static>(mut self, value: Arc<T>) -> Self {
+        self.state.insert(value);
+        self
+    }
+
+    // Adds middleware around every request, the first one added is the
+    // outermost layer
+    pub fn wrap(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    // Registers the routes in the order they were added, the first one
+    // which can't be registered is returned as the error
+    pub fn build(self, config: &ServerConfig) -> Result<ApiEndPointManager, RouteError> {
+        info!("Registering Api Endpoints...");
+        let mut manager = ApiEndPointManager {
             endpoints: Vec::new(),
+            router: Router::new(),
+            state: Arc::new(self.state),
+            middleware: self.middleware,
+            static_files: StaticFiles::new(&config.static_files, &config.compression),
+            compression: Compression::new(&config.compression),
+            trace: config.trace,
         };
-        manager.register_endpoints();
-        manager
-    }
-
-    pub fn handle_request(&self, request: &Request) -> Response {
-        // iterate through endpoints registered
-        // checks if path matches and contains method
-        for endpoint in &self.endpoints {
-            trace!("{}", endpoint.path);
-            let path_match = endpoint.path_matches(&request.request_line.path);
-            let contain_method = endpoint.contain_method(&request.request_line.method);
-
-            return match (path_match, contain_method) {
-                (true, true) => match endpoint.api_type {
-                    ApiType::Static => self.serve_file(request),
-                    ApiType::Function(f) => self.call_function(f, request),
+        for route in self.routes.routes {
+            manager.register_with(route.method, &route.path, route.api_type, route.middleware)?;
+        }
+        if let Some(path) = &config.admin.routes {
+            manager.register_with(vec![HttpMethod::GET], path, ApiType::RouteTable, Vec::new())?;
+        }
+
+        for warning in manager.route_warnings() {
+            warn!("{}", warning);
+        }
+        info!("Routes:\n{}", manager.route_table());
+        Ok(manager)
+    }
+}
+
+impl ApiEndPointManager {
+    pub fn builder() -> ApiEndPointManagerBuilder {
+        ApiEndPointManagerBuilder {
+            routes: RouteGroup::new(),
+            state: AppState::default(),
+            middleware: Vec::new(),
+        }
+    }
+
+    pub fn route_table(&self) -> RouteTable {
+        let routes = self
+            .endpoints
+            .iter()
+            .map(|endpoint| RouteInfo {
+                methods: endpoint.method.iter().map(HttpMethod::as_str).collect(),
+                pattern: endpoint.pattern.as_str().to_string(),
+                handler: match &endpoint.api_type {
+                    ApiType::Static => "static files",
+                    ApiType::Function(handler) => handler.name(),
+                    ApiType::RouteTable => "route table",
                 },
-                (true, false) => Response::builder()
-                    .status_code(StatusCode::MethodNotAllowed)
-                    .build()
-                    .unwrap_or_default(),
+                middleware: endpoint.middleware.iter().map(|layer| layer.name()).collect(),
+            })
+            .collect();
+        RouteTable {
+            middleware: self.middleware.iter().map(|layer| layer.name()).collect(),
+            routes,
+        }
+    }
 
-                (_, _) => continue,
-            };
+    // Routes taking the same method whose patterns overlap, on the paths
+    // both match only the more specific one is reached
+    pub fn route_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (i, first) in self.endpoints.iter().enumerate() {
+            for second in &self.endpoints[i + 1..] {
+                let shared: Vec<&str> = first
+                    .method
+                    .iter()
+                    .filter(|method| second.contain_method(method))
+                    .map(HttpMethod::as_str)
+                    .collect();
+                if shared.is_empty() {
+                    continue;
+                }
+                let (winner, shadowed) = if first.pattern.shadows(&second.pattern) {
+                    (first, second)
+                } else if second.pattern.shadows(&first.pattern) {
+                    (second, first)
+                } else {
+                    continue;
+                };
+                warnings.push(format!(
+                    "Route `{}` shadows `{}` for {} on the paths both match",
+                    winner.pattern.as_str(),
+                    shadowed.pattern.as_str(),
+                    shared.join(", ")
+                ));
+            }
         }
+        warnings
+    }
 
-        Response::builder()
-            .status_code(StatusCode::NotFound)
-            .build()
-            .unwrap_or_default()
+    // Normalizes the path, so the global middleware, routing and static
+    // files all see the same one. Then runs the global middleware around
+    // routing and compresses what it produced. A response to HEAD never has
+    // a body, whichever endpoint produced it.
+    pub fn handle_request(&self, request: &mut Request) -> Response {
+        // The server itself, and TRACE which echoes the target as it came
+        let echoed = request.request_line.method == HttpMethod::TRACE && self.trace;
+        if request.request_line.path != "*" && !echoed {
+            match normalize_path(&request.request_line.path) {
+                Ok(path) => request.request_line.path = path,
+                Err(e) => {
+                    debug!("Rejected path {}: {}", request.request_line.path, e);
+                    return status_response(StatusCode::BadRequest);
+                }
+            }
+        }
+
+        let mut response = run_layers(&self.middleware, request, |request| self.route(request));
+        self.compression.apply(request, &mut response);
+        if request.request_line.method == HttpMethod::HEAD {
+            response.strip_body();
+        }
+        response
     }
 
-    fn serve_file(&self, request: &Request) -> Response {
-        let mut path = PathBuf::from("static");
-        path.push(&request.request_line.path[1..]);
-        debug!("path: {:?}", path);
+    // Routes to the most specific endpoint matching the path which accepts
+    // the method, its captures are stored in request.params. The path is
+    // normalized already.
+    fn route(&self, request: &mut Request) -> Response {
+        // The server itself, only meaningful for OPTIONS
+        if request.request_line.path == "*" {
+            return match request.request_line.method {
+                HttpMethod::OPTIONS => self.options_response(self.endpoints.iter()),
+                _ => status_response(StatusCode::BadRequest),
+            };
+        }
+        if request.request_line.method == HttpMethod::TRACE && self.trace {
+            return trace_response(request);
+        }
 
-        let served_file = File::open(path);
-        match served_file {
-            Ok(mut served_file) => {
-                let mut buf = Vec::new();
-                let _ = served_file.read_to_end(&mut buf);
+        let matches = self.router.lookup(&request.request_line.path);
+        if matches.is_empty() {
+            return Response::builder()
+                .status_code(StatusCode::NotFound)
+                .build()
+                .unwrap_or_default();
+        }
 
-                Response::builder()
-                    .status_code(StatusCode::OK)
-                    .body(buf)
+        let method = &request.request_line.method;
+        let found = matches.iter().find_map(|found| {
+            let endpoints = found.routes.iter().map(|&i| &self.endpoints[i]);
+            find_endpoint(endpoints, method).map(|endpoint| (endpoint, &found.captures))
+        });
+        // Every endpoint for the path, for its Allow header
+        let path_endpoints = matches
+            .iter()
+            .flat_map(|found| found.routes)
+            .map(|&i| &self.endpoints[i]);
+        let (endpoint, captures) = match found {
+            Some(found) => found,
+            None if *method == HttpMethod::OPTIONS => {
+                return self.options_response(path_endpoints);
+            }
+            None => {
+                return Response::builder()
+                    .status_code(StatusCode::MethodNotAllowed)
+                    .header("Allow", &self.allowed_methods(path_endpoints))
                     .build()
-                    .unwrap_or_default()
+                    .unwrap_or_default();
             }
-            Err(e) => {
-                error!("Error in serving static file: {}", e);
-                Response::default()
+        };
+        trace!("Matched {}", endpoint.pattern.as_str());
+
+        let mut params = Params::default();
+        for (name, value) in endpoint.pattern.param_names().zip(captures) {
+            if !name.is_empty() {
+                params.push(name, value);
             }
         }
+
+        request.params = params;
+        request.app_state = Some(Arc::clone(&self.state));
+        run_layers(&endpoint.middleware, request, |request| match &endpoint.api_type {
+            ApiType::Static => self.static_files.serve(request),
+            ApiType::Function(handler) => self.call_function(handler.as_ref(), request),
+            ApiType::RouteTable => Response::builder()
+                .status_code(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(self.route_table().to_json().into())
+                .build()
+                .unwrap_or_default(),
+        })
     }
-    fn call_function(
-        &self,
-        f: fn(&Request) -> Response,
-        request: &Request,
-    ) -> Response {
-        f(request)
+
+    // Methods accepted by endpoints, plus those the server answers itself
+    fn allowed_methods<'a>(&self, endpoints: impl Iterator<Item = &'a ApiEndPoint>) -> String {
+        let mut allowed: Vec<&str> = Vec::new();
+        let mut add = |method: &'static str| {
+            if !allowed.contains(&method) {
+                allowed.push(method);
+            }
+        };
+        for endpoint in endpoints {
+            for method in &endpoint.method {
+                add(method.as_str());
+                if *method == HttpMethod::GET {
+                    add(HttpMethod::HEAD.as_str());
+                }
+            }
+        }
+        add(HttpMethod::OPTIONS.as_str());
+        if self.trace {
+            add(HttpMethod::TRACE.as_str());
+        }
+        allowed.join(", ")
     }
 
-    fn register(&mut self, method: Vec<HttpMethod>, path: &str, api_type: ApiType) {
-        let path = String::from(path);
+    fn options_response<'a>(&self, endpoints: impl Iterator<Item = &'a ApiEndPoint>) -> Response {
+        Response::builder()
+            .status_code(StatusCode::OK)
+            .header("Allow", &self.allowed_methods(endpoints))
+            .build()
+            .unwrap_or_default()
+    }
+
+    fn call_function(&self, handler: &dyn Handler, request: &Request) -> Response {
+        handler.call(request)
+    }
+
+    // Fails when the pattern is malformed, or when an endpoint for the same
+    // paths already takes one of the methods
+    fn register_with(
+        &mut self,
+        method: Vec<HttpMethod>,
+        path: &str,
+        api_type: ApiType,
+        middleware: Vec<Arc<dyn Middleware>>,
+    ) -> Result<(), RouteError> {
+        let pattern = PathPattern::parse(path)?;
+        let index = self.endpoints.len();
+        let same_shape = self.router.insert(&pattern);
+        let existing = same_shape
+            .iter()
+            .map(|&i| &self.endpoints[i])
+            .find(|endpoint| method.iter().any(|method| endpoint.contain_method(method)));
+        if let Some(existing) = existing {
+            return Err(RouteError::Ambiguous {
+                pattern: pattern.as_str().to_string(),
+                existing: existing.pattern.as_str().to_string(),
+            });
+        }
+        same_shape.push(index);
+
         self.endpoints.push(ApiEndPoint {
             method,
-            path,
+            pattern,
             api_type,
+            middleware,
         });
+        Ok(())
     }
 }
+
+fn status_response(status_code: StatusCode) -> Response {
+    Response::builder()
+        .status_code(status_code)
+        .build()
+        .unwrap_or_default()
+}
+
+// Headers likely to hold credentials are left out of the echo
+const TRACE_EXCLUDED_HEADERS: [&str; 3] = ["Authorization", "Proxy-Authorization", "Cookie"];
+
+// Echoes the request head as it was received
+fn trace_response(request: &Request) -> Response {
+    let request_line = &request.request_line;
+    let mut echo = format!("{} {}", request_line.method.as_str(), request_line.path).into_bytes();
+    if let Some(query) = &request_line.query {
+        echo.push(b'?');
+        echo.extend(query.as_bytes());
+    }
+    echo.push(b' ');
+    echo.extend(request_line.protocol.as_bytes());
+    echo.extend(b"\r\n");
+    for (name, value) in &request.headers {
+        let excluded = TRACE_EXCLUDED_HEADERS
+            .iter()
+            .any(|excluded| name.eq_ignore_ascii_case(excluded.as_bytes()));
+        if !excluded {
+            echo.extend(*name);
+            echo.extend(b": ");
+            echo.extend(*value);
+            echo.extend(b"\r\n");
+        }
+    }
+
+    Response::builder()
+        .status_code(StatusCode::OK)
+        .header("Content-Type", "message/http")
+        .body(echo)
+        .build()
+        .unwrap_or_default()
+}
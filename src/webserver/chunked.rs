@@ -0,0 +1,276 @@
+use super::{
+    request::{parse_headers, Headers},
+    response::StatusCode,
+};
+
+#[derive(Clone, Copy)]
+enum State {
+    // Reading the hex chunk size
+    Size { size: usize, digits: usize },
+    // Skipping chunk extensions until the end of the size line
+    Extension { size: usize },
+    SizeLf { size: usize },
+    Data { remaining: usize },
+    DataCr,
+    DataLf,
+    // At the start of a trailer line, or of the final empty line
+    TrailerStart,
+    TrailerLine,
+    TrailerLf,
+    EndLf,
+    Done,
+}
+
+pub enum ChunkedError {
+    Invalid(&'static str),
+    // The body or the trailer section is over its limit
+    TooLarge,
+}
+
+impl ChunkedError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ChunkedError::Invalid(_) => StatusCode::BadRequest,
+            ChunkedError::TooLarge => StatusCode::PayloadTooLarge,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            ChunkedError::Invalid(message) => message,
+            ChunkedError::TooLarge => "Chunked body too large",
+        }
+    }
+}
+
+impl From<&'static str> for ChunkedError {
+    fn from(message: &'static str) -> Self {
+        ChunkedError::Invalid(message)
+    }
+}
+
+// Incremental decoder for a chunked request body (RFC 9112 section 7.1).
+// Bytes can be fed in pieces of any size; decode consumes exactly up to the
+// end of the body, so anything after it belongs to the next request.
+pub struct ChunkedDecoder {
+    state: State,
+    trailer_section: Vec<u8>,
+    // Bytes of body decoded so far
+    decoded: usize,
+    max_body_size: usize,
+    max_trailer_size: usize,
+}
+
+impl ChunkedDecoder {
+    pub fn new(max_body_size: usize, max_trailer_size: usize) -> Self {
+        Self {
+            state: State::Size { size: 0, digits: 0 },
+            trailer_section: Vec::new(),
+            decoded: 0,
+            max_body_size,
+            max_trailer_size,
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    // Decodes input into body, returns the number of bytes consumed. A chunk
+    // which would take the body over its limit is refused before it's read.
+    pub fn decode(&mut self, input: &[u8], body: &mut Vec<u8>) -> Result<usize, ChunkedError> {
+        let mut pos = 0;
+        while pos < input.len() {
+            let byte = input[pos];
+            self.state = match self.state {
+                State::Size { size, digits } => match byte {
+                    b'0'..=b'9' | b'a'..=b'f' | b'A'..=b'F' => {
+                        let digit = (byte as char).to_digit(16).unwrap_or_default() as usize;
+                        let size = size
+                            .checked_mul(16)
+                            .and_then(|size| size.checked_add(digit))
+                            .ok_or("Chunk size too large")?;
+                        State::Size {
+                            size,
+                            digits: digits + 1,
+                        }
+                    }
+                    _ if digits == 0 => return Err("Chunk size missing".into()),
+                    b'\r' => State::SizeLf { size },
+                    b';' | b' ' | b'\t' => State::Extension { size },
+                    _ => return Err("Invalid character in chunk size".into()),
+                },
+                State::Extension { size } => match byte {
+                    b'\r' => State::SizeLf { size },
+                    b'\n' => return Err("Bare LF in chunk extension".into()),
+                    _ => State::Extension { size },
+                },
+                State::SizeLf { size } => match byte {
+                    b'\n' if size == 0 => State::TrailerStart,
+                    b'\n' if size > self.max_body_size - self.decoded => {
+                        return Err(ChunkedError::TooLarge)
+                    }
+                    b'\n' => {
+                        self.decoded += size;
+                        State::Data { remaining: size }
+                    }
+                    _ => return Err("Expected LF after chunk size".into()),
+                },
+                State::Data { remaining } => {
+                    let count = remaining.min(input.len() - pos);
+                    body.extend_from_slice(&input[pos..pos + count]);
+                    pos += count;
+                    self.state = match remaining - count {
+                        0 => State::DataCr,
+                        remaining => State::Data { remaining },
+                    };
+                    continue;
+                }
+                State::DataCr => match byte {
+                    b'\r' => State::DataLf,
+                    _ => return Err("Expected CR after chunk data".into()),
+                },
+                State::DataLf => match byte {
+                    b'\n' => State::Size { size: 0, digits: 0 },
+                    _ => return Err("Expected LF after chunk data".into()),
+                },
+                State::TrailerStart => match byte {
+                    b'\r' => State::EndLf,
+                    _ => {
+                        self.trailer_section.push(byte);
+                        State::TrailerLine
+                    }
+                },
+                State::TrailerLine => {
+                    if self.trailer_section.len() >= self.max_trailer_size {
+                        return Err(ChunkedError::TooLarge);
+                    }
+                    self.trailer_section.push(byte);
+                    match byte {
+                        b'\r' => State::TrailerLf,
+                        _ => State::TrailerLine,
+                    }
+                }
+                State::TrailerLf => {
+                    self.trailer_section.push(byte);
+                    match byte {
+                        b'\n' => State::TrailerStart,
+                        _ => return Err("Expected LF after trailer field".into()),
+                    }
+                }
+                State::EndLf => match byte {
+                    b'\n' => State::Done,
+                    _ => return Err("Expected LF after trailer section".into()),
+                },
+                State::Done => break,
+            };
+            pos += 1;
+        }
+        Ok(pos)
+    }
+
+    // Trailer fields sent after the last chunk
+    pub fn trailers(&self) -> Result<Headers<'_>, &'static str> {
+        parse_headers(&self.trailer_section)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::request::get_header, *};
+
+    const BODY: &[u8] = b"5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nExpires: never\r\n\r\nNEXT";
+
+    // Feeds pieces one after another, like reads off a socket
+    fn decode_pieces(
+        decoder: &mut ChunkedDecoder,
+        pieces: &[&[u8]],
+    ) -> Result<(Vec<u8>, usize), ChunkedError> {
+        let mut body = Vec::new();
+        let mut consumed = 0;
+        for piece in pieces {
+            consumed += decoder.decode(piece, &mut body)?;
+        }
+        Ok((body, consumed))
+    }
+
+    #[test]
+    fn decodes_at_once() {
+        let mut decoder = ChunkedDecoder::new(1024, 1024);
+        let (body, consumed) = decode_pieces(&mut decoder, &[BODY]).ok().unwrap();
+        assert!(decoder.is_done());
+        assert_eq!(body, b"hello, world");
+        // What follows the body is left for the next request
+        assert_eq!(&BODY[consumed..], b"NEXT");
+        let trailers = decoder.trailers().unwrap();
+        assert_eq!(get_header(&trailers, "expires"), Some(&b"never"[..]));
+    }
+
+    #[test]
+    fn decodes_across_every_split() {
+        for split in 0..=BODY.len() {
+            let (first, second) = BODY.split_at(split);
+            let mut decoder = ChunkedDecoder::new(1024, 1024);
+            let (body, consumed) = decode_pieces(&mut decoder, &[first, second]).ok().unwrap();
+            assert!(decoder.is_done(), "split at {}", split);
+            assert_eq!(body, b"hello, world", "split at {}", split);
+            assert_eq!(consumed, BODY.len() - 4, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn decodes_byte_by_byte() {
+        let pieces: Vec<&[u8]> = BODY.chunks(1).collect();
+        let mut decoder = ChunkedDecoder::new(1024, 1024);
+        let (body, consumed) = decode_pieces(&mut decoder, &pieces).ok().unwrap();
+        assert!(decoder.is_done());
+        assert_eq!(body, b"hello, world");
+        assert_eq!(consumed, BODY.len() - 4);
+    }
+
+    #[test]
+    fn waits_for_more_input() {
+        let mut decoder = ChunkedDecoder::new(1024, 1024);
+        let (body, _) = decode_pieces(&mut decoder, &[b"5\r\nhel"]).ok().unwrap();
+        assert!(!decoder.is_done());
+        assert_eq!(body, b"hel");
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let malformed: [&[u8]; 6] = [
+            b"\r\n",
+            b"g\r\n",
+            b"5\nhello\r\n0\r\n\r\n",
+            b"5\r\nhelloX\r\n",
+            b"5;ext\nhello\r\n",
+            b"ffffffffffffffffff\r\n",
+        ];
+        for input in malformed {
+            let mut decoder = ChunkedDecoder::new(usize::MAX, 1024);
+            assert!(
+                matches!(decoder.decode(input, &mut Vec::new()), Err(ChunkedError::Invalid(_))),
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[test]
+    fn limits_body_size() {
+        let mut decoder = ChunkedDecoder::new(12, 1024);
+        assert!(decode_pieces(&mut decoder, &[BODY]).is_ok());
+
+        // The chunk going over the limit is refused before its data arrives
+        let mut decoder = ChunkedDecoder::new(11, 1024);
+        let result = decode_pieces(&mut decoder, &[b"5\r\nhello\r\n", b"7\r\n"]);
+        assert!(matches!(result, Err(ChunkedError::TooLarge)));
+    }
+
+    #[test]
+    fn limits_trailer_size() {
+        let mut decoder = ChunkedDecoder::new(1024, 8);
+        let result = decode_pieces(&mut decoder, &[b"0\r\nExpires: never\r\n\r\n"]);
+        assert!(matches!(result, Err(ChunkedError::TooLarge)));
+    }
+}
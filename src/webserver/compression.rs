@@ -0,0 +1,308 @@
+use std::{
+    io::{self, Cursor, Read, Write},
+    mem,
+};
+
+use brotli::CompressorWriter;
+use flate2::write::GzEncoder;
+use log::error;
+
+use super::{
+    config::{CompressionConfig, ContentCoding},
+    http_utils::quality_values,
+    request::{get_header, Request},
+    response::{Body, Response, StatusCode},
+};
+
+// Levels favouring speed, the compression happens on every request
+const GZIP_LEVEL: u32 = 6;
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+// Bytes of a streamed body compressed before the encoder is flushed. Flushing
+// every small piece would make the output larger than the input.
+const FLUSH_SIZE: usize = 16 * 1024;
+
+// Compresses response bodies for clients accepting one of encodings
+pub struct Compression {
+    enabled: bool,
+    min_size: u64,
+    encodings: Vec<ContentCoding>,
+    mime_types: Vec<String>,
+}
+
+impl Compression {
+    pub fn new(config: &CompressionConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            min_size: config.min_size,
+            encodings: config.encodings.clone(),
+            mime_types: config.mime_types.clone(),
+        }
+    }
+
+    // Leaves alone responses which are encoded already, marked no-transform,
+    // and 206, whose ranges address the uncompressed content. Whenever the
+    // response could have been compressed, Vary says it depends on
+    // Accept-Encoding.
+    pub fn apply(&self, request: &Request, response: &mut Response) {
+        let status_code = response.status_code();
+        if !status_code.allows_content() || matches!(status_code, StatusCode::PartialContent) {
+            return;
+        }
+        if response.get_header("Content-Encoding").is_some() || no_transform(response) {
+            return;
+        }
+        let content_type = response
+            .get_header("Content-Type")
+            .and_then(|content_type| std::str::from_utf8(content_type).ok());
+        if !self.compresses(content_type, response.body_length()) {
+            return;
+        }
+
+        response.add_vary("Accept-Encoding");
+        let coding = match negotiate(request, &self.encodings) {
+            Some(coding) => coding,
+            None => return,
+        };
+        let (body, encoded) = match compress(response.take_body(), coding) {
+            Ok(compressed) => compressed,
+            Err((body, e)) => {
+                error!("Error in compressing with {}: {}", coding.as_str(), e);
+                (body, false)
+            }
+        };
+        response.set_body(body);
+        if !encoded {
+            return;
+        }
+
+        response.set_header("Content-Encoding", coding.as_str());
+        response.remove_header("Content-Length");
+        response.remove_header("Accept-Ranges");
+        weaken_etag(response);
+    }
+
+    // A 304 carries the Vary and the validators of the 200 it stands for,
+    // which apply would have compressed if its content type and length
+    // qualify
+    pub fn not_modified(
+        &self,
+        request: &Request,
+        response: &mut Response,
+        content_type: &str,
+        length: u64,
+    ) {
+        if !self.compresses(Some(content_type), Some(length)) {
+            return;
+        }
+        response.add_vary("Accept-Encoding");
+        if negotiate(request, &self.encodings).is_some() {
+            weaken_etag(response);
+        }
+    }
+
+    // Whether content of this type and length, unknown for a stream, gets
+    // compressed for clients accepting it
+    fn compresses(&self, content_type: Option<&str>, length: Option<u64>) -> bool {
+        self.enabled
+            && !self.encodings.is_empty()
+            && content_type.is_some_and(|content_type| self.is_compressible(content_type))
+            && length.is_none_or(|length| length >= self.min_size)
+    }
+
+    fn is_compressible(&self, content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        let kind = essence.split('/').next().unwrap_or_default();
+        self.mime_types.iter().any(|mime| match mime.strip_suffix("/*") {
+            Some(wildcard) => wildcard.eq_ignore_ascii_case(kind),
+            None => mime.eq_ignore_ascii_case(essence),
+        })
+    }
+}
+
+// The bytes of compressed content differ from those of the uncompressed
+// content, a strong tag would claim they're the same
+fn weaken_etag(response: &mut Response) {
+    if let Some(etag) = response.get_header("ETag").filter(|etag| etag.starts_with(b"\"")) {
+        let weak = format!("W/{}", String::from_utf8_lossy(etag));
+        response.set_header("ETag", &weak);
+    }
+}
+
+// A streamed body is held back until FLUSH_SIZE bytes of it are compressed,
+// a handler whose client should see each piece as it comes opts out with this
+fn no_transform(response: &Response) -> bool {
+    response.get_header("Cache-Control").is_some_and(|cache_control| {
+        String::from_utf8_lossy(cache_control)
+            .split(',')
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+    })
+}
+
+// The coding of available the client likes best, None for sending the
+// content as it is. Ties go to the earlier one in available. `*` stands for
+// any coding the header doesn't name, identity only wins when it's ranked
+// explicitly above the codings.
+pub fn negotiate(request: &Request, available: &[ContentCoding]) -> Option<ContentCoding> {
+    let header = get_header(&request.headers, "Accept-Encoding")
+        .and_then(|header| std::str::from_utf8(header).ok())?;
+    let accepted = quality_values(header);
+    let quality = |name: &str| {
+        accepted
+            .iter()
+            .find(|(accepted, _)| accepted.eq_ignore_ascii_case(name))
+            .map(|&(_, quality)| quality)
+    };
+    let wildcard = quality("*").unwrap_or(0.0);
+
+    let mut best: Option<(ContentCoding, f32)> = None;
+    for &coding in available {
+        // x-gzip is an old name of gzip
+        let coding_quality = match coding {
+            ContentCoding::Gzip => quality("gzip").or_else(|| quality("x-gzip")),
+            _ => quality(coding.as_str()),
+        };
+        let coding_quality = coding_quality.unwrap_or(wildcard);
+        if coding_quality > best.map_or(0.0, |(_, quality)| quality) {
+            best = Some((coding, coding_quality));
+        }
+    }
+    let identity = quality("identity").unwrap_or(0.0);
+    best.filter(|&(_, quality)| quality >= identity)
+        .map(|(coding, _)| coding)
+}
+
+// A body of a known size is compressed at once, and kept as it is if that
+// doesn't make it smaller. Streamed bodies are compressed piece by piece.
+// Along with the body comes whether it was encoded.
+fn compress(body: Body, coding: ContentCoding) -> Result<(Body, bool), (Body, io::Error)> {
+    let mut encoder = match Encoder::new(coding) {
+        Ok(encoder) => encoder,
+        Err(e) => return Err((body, e)),
+    };
+    match body {
+        Body::Bytes(bytes) => {
+            match encoder.write_all(&bytes).and_then(|_| encoder.finish()) {
+                Ok(compressed) if compressed.len() < bytes.len() => {
+                    Ok((Body::Bytes(compressed), true))
+                }
+                Ok(_) => Ok((Body::Bytes(bytes), false)),
+                Err(e) => Err((Body::Bytes(bytes), e)),
+            }
+        }
+        body => {
+            let reader = CompressedBody {
+                body,
+                encoder: Some(encoder),
+                output: Cursor::default(),
+                unflushed: 0,
+            };
+            let body = Body::Reader {
+                reader: Box::new(reader),
+                length: None,
+            };
+            Ok((body, true))
+        }
+    }
+}
+
+// Compresses into a Vec, from which the output is taken as it's produced
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Br(Box<CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(coding: ContentCoding) -> io::Result<Self> {
+        Ok(match coding {
+            ContentCoding::Gzip => Encoder::Gzip(GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(GZIP_LEVEL),
+            )),
+            ContentCoding::Br => Encoder::Br(Box::new(CompressorWriter::new(
+                Vec::new(),
+                0,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            ContentCoding::Zstd => Encoder::Zstd(zstd::Encoder::new(Vec::new(), ZSTD_LEVEL)?),
+        })
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write_all(data),
+            Encoder::Br(encoder) => encoder.write_all(data),
+            Encoder::Zstd(encoder) => encoder.write_all(data),
+        }
+    }
+
+    // Pushes out everything written so far, so a streamed body reaches the
+    // client without waiting for the encoder's own buffer to fill
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Br(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            Encoder::Gzip(encoder) => mem::take(encoder.get_mut()),
+            Encoder::Br(encoder) => mem::take(encoder.get_mut()),
+            Encoder::Zstd(encoder) => mem::take(encoder.get_mut()),
+        }
+    }
+
+    // The rest of the output, the stream's trailer included
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Br(encoder) => Ok(encoder.into_inner()),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+// Compressed form of a streamed body, read as the body is
+struct CompressedBody {
+    body: Body,
+    // None once the stream is finished
+    encoder: Option<Encoder>,
+    output: Cursor<Vec<u8>>,
+    // Bytes written to the encoder since it was last flushed
+    unflushed: usize,
+}
+
+impl Read for CompressedBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.output.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            let mut encoder = match self.encoder.take() {
+                Some(encoder) => encoder,
+                None => return Ok(0),
+            };
+            let output = match self.body.next_data()? {
+                Some(data) => {
+                    encoder.write_all(&data)?;
+                    self.unflushed += data.len();
+                    if self.unflushed >= FLUSH_SIZE {
+                        encoder.flush()?;
+                        self.unflushed = 0;
+                    }
+                    let output = encoder.take_output();
+                    self.encoder = Some(encoder);
+                    output
+                }
+                None => encoder.finish()?,
+            };
+            self.output = Cursor::new(output);
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{
+    http_utils::parse_http_date,
+    request::{get_header, HttpMethod, Request},
+};
+
+// What a client can hold on to in order to validate its cached copy
+pub struct Validators {
+    // Strong entity tag, quotes included
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+pub enum Precondition {
+    Proceed,
+    // 304, the client's copy is current
+    NotModified,
+    // 412
+    Failed,
+}
+
+// Evaluates If-Match, If-Unmodified-Since, If-None-Match and
+// If-Modified-Since in the order of RFC 9110 section 13.2.2. A date that
+// doesn't parse makes its header ignored.
+pub fn evaluate(request: &Request, validators: &Validators) -> Precondition {
+    let header = |name| {
+        get_header(&request.headers, name).and_then(|value| std::str::from_utf8(value).ok())
+    };
+    let modified_secs = validators.last_modified.map(unix_secs);
+
+    if let Some(if_match) = header("If-Match") {
+        if !etag_matches(if_match, &validators.etag, true) {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = header("If-Unmodified-Since").and_then(parse_http_date) {
+        if modified_secs.is_some_and(|modified| modified > unix_secs(since)) {
+            return Precondition::Failed;
+        }
+    }
+
+    let method = &request.request_line.method;
+    let is_read = *method == HttpMethod::GET || *method == HttpMethod::HEAD;
+    if let Some(if_none_match) = header("If-None-Match") {
+        if etag_matches(if_none_match, &validators.etag, false) {
+            return if is_read {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if let Some(since) = header("If-Modified-Since").and_then(parse_http_date) {
+        if is_read && modified_secs.is_some_and(|modified| modified <= unix_secs(since)) {
+            return Precondition::NotModified;
+        }
+    }
+    Precondition::Proceed
+}
+
+// If-Range holds either an entity tag, compared strongly, or a date which
+// must be exactly the modification time
+pub fn if_range_matches(validator: &[u8], validators: &Validators) -> bool {
+    let validator = match std::str::from_utf8(validator) {
+        Ok(validator) => validator.trim(),
+        Err(_) => return false,
+    };
+    if validator.starts_with('"') || validator.starts_with("W/") {
+        return validator == validators.etag;
+    }
+    match (parse_http_date(validator), validators.last_modified) {
+        (Some(date), Some(modified)) => unix_secs(date) == unix_secs(modified),
+        _ => false,
+    }
+}
+
+// Whether a list of entity tags, or `*`, matches etag. The weak comparison
+// ignores the W/ prefix, the strong one never matches a weak tag.
+fn etag_matches(list: &str, etag: &str, strong: bool) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+
+    let mut rest = list;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            return false;
+        }
+        let (weak, tagged) = match rest.strip_prefix("W/") {
+            Some(tagged) => (true, tagged),
+            None => (false, rest),
+        };
+        // A malformed tag ends the list
+        let end = match tagged.strip_prefix('"').and_then(|tag| tag.find('"')) {
+            Some(end) => end + 2,
+            None => return false,
+        };
+        if &tagged[..end] == etag && !(strong && weak) {
+            return true;
+        }
+        rest = &tagged[end..];
+    }
+}
+
+// HTTP dates have a resolution of one second
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        super::{
+            http_utils::{format_http_date, HttpProtocol},
+            request::{Headers, RequestLine},
+        },
+        *,
+    };
+
+    const MODIFIED_SECS: u64 = 1_000_000_000;
+
+    fn validators() -> Validators {
+        Validators {
+            etag: String::from("\"abc\""),
+            last_modified: Some(UNIX_EPOCH + Duration::from_secs(MODIFIED_SECS)),
+        }
+    }
+
+    fn date(secs: u64) -> String {
+        format_http_date(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    fn evaluate_with(method: HttpMethod, headers: &[(&str, &str)]) -> Precondition {
+        let headers: Headers = headers
+            .iter()
+            .map(|(name, value)| (name.as_bytes(), value.as_bytes()))
+            .collect();
+        let request_line = RequestLine {
+            method,
+            path: String::from("/"),
+            query: None,
+            protocol: HttpProtocol::HTTP1_1,
+        };
+        let request = Request::new(request_line, headers, b"", Headers::new());
+        evaluate(&request, &validators())
+    }
+
+    #[test]
+    fn etag_matches_weakly() {
+        assert!(etag_matches("\"abc\"", "\"abc\"", false));
+        assert!(etag_matches("W/\"abc\"", "\"abc\"", false));
+        assert!(etag_matches("\"x\", W/\"abc\"", "\"abc\"", false));
+        assert!(etag_matches(" * ", "\"abc\"", false));
+        assert!(!etag_matches("\"abcd\"", "\"abc\"", false));
+        assert!(!etag_matches("", "\"abc\"", false));
+    }
+
+    #[test]
+    fn etag_matches_strongly() {
+        assert!(etag_matches("\"abc\"", "\"abc\"", true));
+        assert!(etag_matches("W/\"x\",\"abc\"", "\"abc\"", true));
+        assert!(etag_matches("*", "\"abc\"", true));
+        assert!(!etag_matches("W/\"abc\"", "\"abc\"", true));
+    }
+
+    #[test]
+    fn etag_matches_stops_at_a_malformed_tag() {
+        assert!(!etag_matches("abc, \"abc\"", "\"abc\"", false));
+        assert!(!etag_matches("\"abc", "\"abc\"", false));
+    }
+
+    #[test]
+    fn evaluate_without_preconditions() {
+        assert!(matches!(evaluate_with(HttpMethod::GET, &[]), Precondition::Proceed));
+    }
+
+    #[test]
+    fn evaluate_if_none_match() {
+        // The weak comparison, a compressed representation's W/ tag matches
+        let not_modified = evaluate_with(HttpMethod::GET, &[("If-None-Match", "W/\"abc\"")]);
+        assert!(matches!(not_modified, Precondition::NotModified));
+        let head = evaluate_with(HttpMethod::HEAD, &[("if-none-match", "\"abc\"")]);
+        assert!(matches!(head, Precondition::NotModified));
+        let other = evaluate_with(HttpMethod::GET, &[("If-None-Match", "\"x\"")]);
+        assert!(matches!(other, Precondition::Proceed));
+        let put = evaluate_with(HttpMethod::PUT, &[("If-None-Match", "*")]);
+        assert!(matches!(put, Precondition::Failed));
+    }
+
+    #[test]
+    fn evaluate_if_match() {
+        let strong = evaluate_with(HttpMethod::PUT, &[("If-Match", "\"abc\"")]);
+        assert!(matches!(strong, Precondition::Proceed));
+        // The strong comparison, a weak tag never matches
+        let weak = evaluate_with(HttpMethod::PUT, &[("If-Match", "W/\"abc\"")]);
+        assert!(matches!(weak, Precondition::Failed));
+    }
+
+    #[test]
+    fn evaluate_dates() {
+        let modified = date(MODIFIED_SECS);
+        let earlier = date(MODIFIED_SECS - 1);
+        let cases = [
+            ("If-Modified-Since", &modified, Precondition::NotModified),
+            ("If-Modified-Since", &earlier, Precondition::Proceed),
+            ("If-Unmodified-Since", &modified, Precondition::Proceed),
+            ("If-Unmodified-Since", &earlier, Precondition::Failed),
+        ];
+        for (name, value, expected) in cases {
+            let result = evaluate_with(HttpMethod::GET, &[(name, value)]);
+            assert_eq!(
+                std::mem::discriminant(&result),
+                std::mem::discriminant(&expected),
+                "{}: {}",
+                name,
+                value
+            );
+        }
+        let invalid = evaluate_with(HttpMethod::GET, &[("If-Unmodified-Since", "yesterday")]);
+        assert!(matches!(invalid, Precondition::Proceed));
+    }
+
+    #[test]
+    fn evaluate_entity_tags_take_precedence_over_dates() {
+        let earlier = date(MODIFIED_SECS - 1);
+        let if_match = evaluate_with(
+            HttpMethod::GET,
+            &[("If-Match", "\"abc\""), ("If-Unmodified-Since", &earlier)],
+        );
+        assert!(matches!(if_match, Precondition::Proceed));
+
+        let modified = date(MODIFIED_SECS);
+        let if_none_match = evaluate_with(
+            HttpMethod::GET,
+            &[("If-None-Match", "\"x\""), ("If-Modified-Since", &modified)],
+        );
+        assert!(matches!(if_none_match, Precondition::Proceed));
+    }
+
+    #[test]
+    fn evaluate_if_match_before_if_none_match() {
+        let result = evaluate_with(
+            HttpMethod::GET,
+            &[("If-Match", "\"x\""), ("If-None-Match", "\"abc\"")],
+        );
+        assert!(matches!(result, Precondition::Failed));
+    }
+}
@@ -0,0 +1,536 @@
+use std::{collections::HashMap, fmt, fs, io, net::ToSocketAddrs, path::PathBuf, time::Duration};
+
+use serde::{
+    de::{self, value::MapAccessDeserializer, value::SeqAccessDeserializer, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use toml::{Table, Value};
+
+pub const DEFAULT_CONFIG_PATH: &str = "server-config.toml";
+// Environment variables overriding the config, sections are separated by a
+// double underscore: WEBSERVER_KEEP_ALIVE__TIMEOUT=10
+const ENV_PREFIX: &str = "WEBSERVER_";
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerType {
+    // A thread per connection
+    #[default]
+    Single,
+    // A fixed pool of worker threads
+    Multi,
+    // A single epoll reactor
+    Event,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // Addresses served by the same endpoints, `[::]:port` takes IPv4 too
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen: Vec<ListenConfig>,
+    pub server_type: ServerType,
+    // Time in-flight requests get to finish after SIGINT/SIGTERM
+    #[serde(deserialize_with = "seconds")]
+    pub shutdown_timeout: Duration,
+    // Answer TRACE by echoing the request head back
+    pub trace: bool,
+    pub keep_alive: KeepAliveConfig,
+    pub limits: LimitsConfig,
+    pub thread_pool: ThreadPoolConfig,
+    pub static_files: StaticFilesConfig,
+    pub compression: CompressionConfig,
+    pub admin: AdminConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![ListenConfig::from("localhost:8080")],
+            server_type: ServerType::default(),
+            shutdown_timeout: Duration::from_secs(30),
+            trace: false,
+            keep_alive: KeepAliveConfig::default(),
+            limits: LimitsConfig::default(),
+            thread_pool: ThreadPoolConfig::default(),
+            static_files: StaticFilesConfig::default(),
+            compression: CompressionConfig::default(),
+            admin: AdminConfig::default(),
+        }
+    }
+}
+
+// A listen address, written as "host:port" or as a table when it has TLS
+#[derive(Debug)]
+pub struct ListenConfig {
+    pub address: String,
+    pub tls: Option<TlsConfig>,
+}
+
+impl From<&str> for ListenConfig {
+    fn from(address: &str) -> Self {
+        Self {
+            address: address.to_string(),
+            tls: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenTable {
+    address: String,
+    tls: Option<TlsConfig>,
+}
+
+struct ListenVisitor;
+
+impl<'de> Visitor<'de> for ListenVisitor {
+    type Value = ListenConfig;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an address or a table with `address` and `tls`")
+    }
+
+    fn visit_str<E: de::Error>(self, address: &str) -> Result<Self::Value, E> {
+        Ok(ListenConfig::from(address))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let table = ListenTable::deserialize(MapAccessDeserializer::new(map))?;
+        Ok(ListenConfig {
+            address: table.address,
+            tls: table.tls,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ListenVisitor)
+    }
+}
+
+// Certificates served on a TLS listener
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct TlsConfig {
+    pub certificates: Vec<TlsCertificate>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct TlsCertificate {
+    // PEM file, leaf certificate first
+    pub cert_chain: PathBuf,
+    // PEM file with a PKCS#8, PKCS#1 or SEC1 key
+    pub private_key: PathBuf,
+    // SNI names the certificate is picked for, `*.example.com` matches one
+    // label. Clients sending another name or none get the first certificate
+    // without names, or the first certificate.
+    #[serde(default)]
+    pub server_names: Vec<String>,
+}
+
+// Limits for persistent (keep-alive) connections
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeepAliveConfig {
+    // How long an idle connection waits for its next request
+    #[serde(deserialize_with = "seconds")]
+    pub timeout: Duration,
+    // Number of requests served on one connection before it is closed
+    pub max_requests: usize,
+}
+
+impl Default for KeepAliveConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            max_requests: 100,
+        }
+    }
+}
+
+// Sizes of a request, larger requests are answered with an error and the
+// connection is closed
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // Bytes of the request line and headers, 431 above. Also bounds the
+    // trailer section of a chunked body.
+    pub max_head_size: usize,
+    // Bytes of the body after chunked decoding, 413 above
+    pub max_body_size: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_head_size: 16 * 1024,
+            max_body_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+// Sizing of the worker pool used by the multithreaded server
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThreadPoolConfig {
+    pub workers: usize,
+    // Connections waiting for a free worker before new ones are turned away
+    pub queue_capacity: usize,
+    // Stack size of each worker in bytes, platform default when None
+    pub stack_size: Option<usize>,
+    // Sent in Retry-After when the queue is full
+    #[serde(deserialize_with = "seconds")]
+    pub retry_after: Duration,
+}
+
+impl Default for ThreadPoolConfig {
+    fn default() -> Self {
+        Self {
+            workers: 1024,
+            queue_capacity: 1024,
+            stack_size: None,
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+// Files served by static routes
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct StaticFilesConfig {
+    // Directory the request path is resolved in, nothing outside of it is
+    // served
+    pub root: PathBuf,
+    // Extension to Content-Type, replacing or adding to the built-in table
+    pub mime_types: HashMap<String, String>,
+    // Added to the Content-Type of text files, none when empty
+    pub charset: String,
+    // Send X-Content-Type-Options: nosniff with files
+    pub nosniff: bool,
+    pub etag: EtagSource,
+    // Served for a directory, the first one present wins
+    pub index_files: Vec<String>,
+    // List directories without an index file, instead of answering 404
+    pub directory_listing: bool,
+    // Serve `file.gz` or `file.br` in place of `file` to clients accepting
+    // the encoding
+    pub precompressed: bool,
+}
+
+// What the ETag of a file is derived from
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EtagSource {
+    // Size and modification time, free to compute
+    #[default]
+    Metadata,
+    // A hash of the file, stays the same when a file is rewritten unchanged.
+    // Computed once per file version and cached.
+    Content,
+}
+
+impl Default for StaticFilesConfig {
+    fn default() -> Self {
+        Self {
+            root: PathBuf::from("static"),
+            mime_types: HashMap::new(),
+            charset: String::from("utf-8"),
+            nosniff: false,
+            etag: EtagSource::default(),
+            index_files: vec![String::from("index.html")],
+            directory_listing: false,
+            precompressed: true,
+        }
+    }
+}
+
+// Compressing responses while they are sent
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // Bodies of a known length below this many bytes are sent as they are
+    pub min_size: u64,
+    // Offered to clients, the first one wins when a client likes several
+    // equally
+    pub encodings: Vec<ContentCoding>,
+    // Content types worth compressing, `type/*` matches a whole type
+    pub mime_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_size: 1024,
+            encodings: vec![ContentCoding::Zstd, ContentCoding::Br, ContentCoding::Gzip],
+            mime_types: [
+                "text/*",
+                "application/json",
+                "application/manifest+json",
+                "application/javascript",
+                "application/xml",
+                "application/wasm",
+                "image/svg+xml",
+                "font/ttf",
+                "font/otf",
+                "application/vnd.ms-fontobject",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentCoding {
+    Gzip,
+    // Brotli
+    Br,
+    Zstd,
+}
+
+impl ContentCoding {
+    // Name in Accept-Encoding and Content-Encoding
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Br => "br",
+            ContentCoding::Zstd => "zstd",
+        }
+    }
+
+    // Of a precompressed static file
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ContentCoding::Gzip => "gz",
+            ContentCoding::Br => "br",
+            ContentCoding::Zstd => "zst",
+        }
+    }
+}
+
+// Endpoints describing the server itself, all disabled by default
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    // Path serving the route table as JSON
+    pub routes: Option<String>,
+}
+
+// Durations are written as whole seconds
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+// A single address can be given without the list
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<ListenConfig>, D::Error> {
+    struct OneOrMany;
+
+    impl<'de> Visitor<'de> for OneOrMany {
+        type Value = Vec<ListenConfig>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an address, a listen table or a list of them")
+        }
+
+        fn visit_str<E: de::Error>(self, address: &str) -> Result<Self::Value, E> {
+            ListenVisitor.visit_str(address).map(|listen| vec![listen])
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+            ListenVisitor.visit_map(map).map(|listen| vec![listen])
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+            Vec::deserialize(SeqAccessDeserializer::new(seq))
+        }
+    }
+
+    deserializer.deserialize_any(OneOrMany)
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, io::Error),
+    Parse(String, toml::de::Error),
+    Override(toml::de::Error),
+    InvalidOverride(String),
+    InvalidAddress(String),
+    Tls(String, String),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "Cannot read {}: {}", path, e),
+            ConfigError::Parse(path, e) => write!(f, "Invalid config file {}: {}", path, e),
+            ConfigError::Override(e) => write!(f, "Invalid override: {}", e),
+            ConfigError::InvalidOverride(key) => write!(f, "Invalid override key `{}`", key),
+            ConfigError::InvalidAddress(address) => {
+                write!(f, "Invalid listen address `{}`, expected host:port", address)
+            }
+            ConfigError::Tls(address, message) => {
+                write!(f, "Invalid TLS config of {}: {}", address, message)
+            }
+            ConfigError::Invalid(message) => write!(f, "Invalid config: {}", message),
+        }
+    }
+}
+
+impl ServerConfig {
+    // Reads the config file at path and applies the overrides in order, a
+    // later override of the same key wins.
+    // Keys are dotted paths into the file: `listen` or `keep_alive.timeout`.
+    pub fn load(path: &str, overrides: &[(String, String)]) -> Result<Self, ConfigError> {
+        let config_str =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_string(), e))?;
+
+        // Deserialized on its own first, so mistakes in the file are
+        // reported with their line
+        let config: ServerConfig =
+            toml::from_str(&config_str).map_err(|e| ConfigError::Parse(path.to_string(), e))?;
+
+        let config = if overrides.is_empty() {
+            config
+        } else {
+            let mut table: Table = toml::from_str(&config_str)
+                .map_err(|e| ConfigError::Parse(path.to_string(), e))?;
+            for (key, value) in overrides {
+                apply_override(&mut table, key, value)?;
+            }
+            Value::Table(table)
+                .try_into()
+                .map_err(ConfigError::Override)?
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.is_empty() {
+            return Err(ConfigError::Invalid("listen needs at least one address"));
+        }
+        for listen in &self.listen {
+            if listen.address.to_socket_addrs().is_err() {
+                return Err(ConfigError::InvalidAddress(listen.address.clone()));
+            }
+            if let Some(tls) = &listen.tls {
+                validate_tls(&listen.address, tls)?;
+            }
+        }
+        if self.thread_pool.workers == 0 {
+            return Err(ConfigError::Invalid("thread_pool.workers must be at least 1"));
+        }
+        if !self.static_files.root.is_dir() {
+            return Err(ConfigError::Invalid("static_files.root must be a directory"));
+        }
+        // Both end up in a header
+        let is_mime = |mime: &str| {
+            mime.split_once('/')
+                .is_some_and(|(kind, subtype)| !kind.is_empty() && !subtype.is_empty())
+                && mime.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+        };
+        if !self.static_files.mime_types.values().all(|mime| is_mime(mime)) {
+            return Err(ConfigError::Invalid(
+                "static_files.mime_types values must look like type/subtype",
+            ));
+        }
+        let is_file_name = |name: &str| {
+            !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+        };
+        if !self.static_files.index_files.iter().all(|name| is_file_name(name)) {
+            return Err(ConfigError::Invalid(
+                "static_files.index_files must be file names without a directory",
+            ));
+        }
+        let is_token = |b: u8| b.is_ascii_alphanumeric() || b"-_.".contains(&b);
+        if !self.static_files.charset.bytes().all(is_token) {
+            return Err(ConfigError::Invalid("static_files.charset is not a charset name"));
+        }
+        if !self.compression.mime_types.iter().all(|mime| is_mime(mime)) {
+            return Err(ConfigError::Invalid(
+                "compression.mime_types must look like type/subtype or type/*",
+            ));
+        }
+        if self.limits.max_head_size == 0 {
+            return Err(ConfigError::Invalid("limits.max_head_size must be at least 1"));
+        }
+        // A socket can't be given a zero read timeout
+        if self.keep_alive.timeout.is_zero() {
+            return Err(ConfigError::Invalid("keep_alive.timeout must be at least 1"));
+        }
+        if self.keep_alive.max_requests == 0 {
+            return Err(ConfigError::Invalid(
+                "keep_alive.max_requests must be at least 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Loads the certificates, so check-config catches unreadable or mismatched files
+#[cfg(feature = "tls")]
+fn validate_tls(address: &str, tls: &TlsConfig) -> Result<(), ConfigError> {
+    super::tls::server_config(tls)
+        .map(|_| ())
+        .map_err(|e| ConfigError::Tls(address.to_string(), e))
+}
+
+#[cfg(not(feature = "tls"))]
+fn validate_tls(address: &str, _tls: &TlsConfig) -> Result<(), ConfigError> {
+    Err(ConfigError::Tls(
+        address.to_string(),
+        String::from("the server was built without the `tls` feature"),
+    ))
+}
+
+// Overrides taken from WEBSERVER_* environment variables
+pub fn env_overrides(vars: impl Iterator<Item = (String, String)>) -> Vec<(String, String)> {
+    vars.filter_map(|(key, value)| {
+        let key = key.strip_prefix(ENV_PREFIX)?;
+        Some((key.to_lowercase().replace("__", "."), value))
+    })
+    .collect()
+}
+
+fn apply_override(table: &mut Table, key: &str, value: &str) -> Result<(), ConfigError> {
+    let key = key.replace('-', "_");
+    let mut sections: Vec<&str> = key.split('.').collect();
+    let field = match sections.pop() {
+        Some(field) if !field.is_empty() => field,
+        _ => return Err(ConfigError::InvalidOverride(key.clone())),
+    };
+
+    let mut current = table;
+    for section in sections {
+        let entry = current
+            .entry(section)
+            .or_insert_with(|| Value::Table(Table::new()));
+        current = match entry {
+            Value::Table(table) => table,
+            _ => return Err(ConfigError::InvalidOverride(key.clone())),
+        };
+    }
+    current.insert(field.to_string(), parse_value(value));
+    Ok(())
+}
+
+// Values are read as TOML (numbers, booleans, arrays), anything else is
+// taken as a plain string
+fn parse_value(raw: &str) -> Value {
+    format!("value = {}", raw)
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(raw.to_string()))
+}
@@ -0,0 +1,145 @@
+use std::{cmp::Ordering, fmt::Write, fs, io, path::Path, time::SystemTime};
+
+use super::{
+    http_utils::{format_http_date, percent_encode_path},
+    json,
+};
+
+pub struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Clone, Copy)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    // Value of the `sort` query parameter, name when unknown
+    pub fn parse(value: Option<&str>) -> Self {
+        match value {
+            Some("size") => SortKey::Size,
+            Some("mtime") => SortKey::Modified,
+            _ => SortKey::Name,
+        }
+    }
+}
+
+// Entries of a directory, hidden ones and those which can't be read left out
+pub fn read_entries(path: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) if !name.starts_with('.') => name,
+            _ => continue,
+        };
+        // Follows symlinks, like serving the entry would
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    Ok(entries)
+}
+
+// Ties are broken by name
+pub fn sort(entries: &mut [Entry], key: SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let ordering = match key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        };
+        let ordering = ordering.then_with(|| a.name.cmp(&b.name));
+        if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    });
+}
+
+// path is the decoded request path of the directory, ending with `/`
+pub fn html(path: &str, entries: &[Entry]) -> String {
+    let title = format!("Index of {}", escape_html(path));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+         <body>\n<h1>{0}</h1>\n<table>\n\
+         <tr><th><a href=\"?sort=name\">Name</a></th><th><a href=\"?sort=size\">Size</a></th>\
+         <th><a href=\"?sort=mtime\">Modified</a></th></tr>\n",
+        title
+    );
+    if path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            String::from("-")
+        } else {
+            entry.size.to_string()
+        };
+        let _ = writeln!(
+            html,
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+            escape_html(&percent_encode_path(&entry.name)),
+            suffix,
+            escape_html(&entry.name),
+            suffix,
+            size,
+            entry.modified.map(format_http_date).unwrap_or_default(),
+        );
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+pub fn json(path: &str, entries: &[Entry]) -> String {
+    let mut json = String::from("{\"path\":");
+    json::push_string(&mut json, path);
+    json.push_str(",\"entries\":[");
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        json.push_str("{\"name\":");
+        json::push_string(&mut json, &entry.name);
+        json.push_str(",\"type\":");
+        json::push_string(&mut json, if entry.is_dir { "directory" } else { "file" });
+        let _ = write!(json, ",\"size\":{},\"modified\":", entry.size);
+        match entry.modified {
+            Some(modified) => json::push_string(&mut json, &format_http_date(modified)),
+            None => json.push_str("null"),
+        }
+        json.push('}');
+    }
+    json.push_str("]}");
+    json
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,638 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, ErrorKind, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, trace, warn};
+
+use super::{
+    api_endpoint_manager::ApiEndPointManager,
+    chunked::ChunkedDecoder,
+    request::{
+        body_framing, find_head_end, parse_request_before_body, wants_keep_alive, BodyFraming,
+        Headers, Request,
+    },
+    http_utils::HttpProtocol,
+    listener::Listener,
+    response::{Response, ResponseEncoder, StatusCode},
+    config::{KeepAliveConfig, LimitsConfig},
+    run::{encode_response, error_response, HeaderDebugWrapper},
+    shutdown,
+    stream::Stream,
+};
+
+const SHUTDOWN_TOKEN: u64 = u64::MAX;
+// Listener i is registered as FIRST_LISTENER_TOKEN + i, connections count up
+// from 0 and never get near
+const FIRST_LISTENER_TOKEN: u64 = 1 << 63;
+const MAX_EVENTS: usize = 1024;
+const READ_CHUNK_SIZE: usize = 4096;
+const WRITE_BUF_SIZE: usize = 64 * 1024;
+// Upper bound on how late an idle connection is noticed
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const READ_INTEREST: u32 = (libc::EPOLLIN | libc::EPOLLRDHUP) as u32;
+const WRITE_INTEREST: u32 = READ_INTEREST | libc::EPOLLOUT as u32;
+
+// Thin wrapper around an epoll instance. Every registered fd is identified by
+// a token which is handed back in the events returned by wait.
+struct Epoll {
+    fd: OwnedFd,
+}
+
+impl Epoll {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        Ok(Self { fd })
+    }
+
+    fn add(&self, fd: RawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, token, interest)
+    }
+
+    fn modify(&self, fd: RawFd, token: u64, interest: u32) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_MOD, fd, token, interest)
+    }
+
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, token: u64, interest: u32) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: interest,
+            u64: token,
+        };
+        let result = unsafe { libc::epoll_ctl(self.fd.as_raw_fd(), op, fd, &mut event) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn wait(&self, events: &mut [libc::epoll_event], timeout_ms: i32) -> io::Result<usize> {
+        let result = unsafe {
+            libc::epoll_wait(
+                self.fd.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.len() as i32,
+                timeout_ms,
+            )
+        };
+        if result < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(e);
+        }
+        Ok(result as usize)
+    }
+}
+
+// Queues the response behind earlier ones on the connection, returns whether
+// the connection can be kept open afterwards
+fn queue_response(
+    pending: &mut VecDeque<ResponseEncoder>,
+    response: Response,
+    protocol: &HttpProtocol,
+    keep_alive: bool,
+) -> bool {
+    let (encoder, keep_alive) = encode_response(response, protocol, keep_alive);
+    pending.push_back(encoder);
+    keep_alive
+}
+
+// A chunked request body which hasn't fully arrived yet. What the decoder
+// consumed is removed from read_buf, which keeps only the head.
+struct ChunkedBody {
+    decoder: ChunkedDecoder,
+    body: Vec<u8>,
+}
+
+impl ChunkedBody {
+    fn new(limits: &LimitsConfig) -> Self {
+        Self {
+            decoder: ChunkedDecoder::new(limits.max_body_size, limits.max_head_size),
+            body: Vec::new(),
+        }
+    }
+}
+
+struct Connection {
+    stream: Stream,
+    read_buf: Vec<u8>,
+    write_buf: Vec<u8>,
+    written: usize,
+    // Responses not yet moved to write_buf, in request order
+    pending: VecDeque<ResponseEncoder>,
+    interest: u32,
+    peer_closed: bool,
+    close_after_write: bool,
+    served: usize,
+    last_active: Instant,
+    chunked_body: Option<ChunkedBody>,
+}
+
+impl Connection {
+    fn new(stream: Stream) -> Self {
+        Self {
+            stream,
+            read_buf: Vec::new(),
+            write_buf: Vec::new(),
+            written: 0,
+            pending: VecDeque::new(),
+            interest: READ_INTEREST,
+            peer_closed: false,
+            close_after_write: false,
+            served: 0,
+            last_active: Instant::now(),
+            chunked_body: None,
+        }
+    }
+
+    // Returns false when the connection should be closed
+    fn on_readable(
+        &mut self,
+        manager: &ApiEndPointManager,
+        keep_alive: &KeepAliveConfig,
+        limits: &LimitsConfig,
+    ) -> bool {
+        self.last_active = Instant::now();
+        // Fits the largest request allowed, a full buffer is processed before
+        // reading on
+        let capacity = limits.max_head_size.saturating_add(limits.max_body_size);
+        loop {
+            let full = match self.fill_read_buf(capacity) {
+                Ok(full) => full,
+                Err(e) => {
+                    error!("Error in reading from connection: {}", e);
+                    return false;
+                }
+            };
+
+            if let Err(e) = self.process_requests(manager, keep_alive, limits) {
+                error!("Parsing Error! {}", e);
+                return false;
+            }
+            if !full || self.close_after_write {
+                break;
+            }
+        }
+
+        if self.peer_closed && self.write_buf.is_empty() && self.pending.is_empty() {
+            return false;
+        }
+
+        self.on_writable()
+    }
+
+    // Reads until the socket has nothing more or read_buf holds capacity
+    // bytes, returns whether it stopped for the latter. Once the connection
+    // is to be closed, what arrives is dropped.
+    fn fill_read_buf(&mut self, capacity: usize) -> io::Result<bool> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        loop {
+            if self.read_buf.len() >= capacity {
+                return Ok(true);
+            }
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.peer_closed = true;
+                    return Ok(false);
+                }
+                Ok(_) if self.close_after_write => {}
+                Ok(n) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                // TLS peer closing without close_notify
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    self.peer_closed = true;
+                    return Ok(false);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Returns false when the connection should be closed
+    fn on_writable(&mut self) -> bool {
+        self.last_active = Instant::now();
+        loop {
+            if let Err(e) = self.fill_write_buf() {
+                error!("Error in producing response body: {}", e);
+                return false;
+            }
+            if self.write_buf.is_empty() {
+                break;
+            }
+
+            while self.written < self.write_buf.len() {
+                match self.stream.write(&self.write_buf[self.written..]) {
+                    Ok(0) => return false,
+                    Ok(n) => self.written += n,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return true,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        error!("Error in writing response: {}", e);
+                        return false;
+                    }
+                }
+            }
+
+            self.write_buf.clear();
+            self.written = 0;
+        }
+
+        match self.stream.write_pending() {
+            Ok(true) => {}
+            Ok(false) => return true,
+            Err(e) => {
+                error!("Error in writing response: {}", e);
+                return false;
+            }
+        }
+
+        !self.close_after_write
+    }
+
+    // Pulls pieces of the pending responses until enough is buffered, so a
+    // streamed body is only read as fast as the client receives it
+    fn fill_write_buf(&mut self) -> io::Result<()> {
+        while self.write_buf.len() < WRITE_BUF_SIZE {
+            let encoder = match self.pending.front_mut() {
+                Some(encoder) => encoder,
+                None => break,
+            };
+            match encoder.next_bytes()? {
+                Some(bytes) => {
+                    debug!("raw_response\n{}", String::from_utf8_lossy(&bytes));
+                    self.write_buf.extend(bytes);
+                }
+                None => {
+                    self.pending.pop_front();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Handles every buffered request whose head and body have fully arrived
+    fn process_requests(
+        &mut self,
+        manager: &ApiEndPointManager,
+        keep_alive: &KeepAliveConfig,
+        limits: &LimitsConfig,
+    ) -> Result<(), &'static str> {
+        while !self.close_after_write {
+            let head_end = match find_head_end(&self.read_buf) {
+                Some(head_end) if head_end <= limits.max_head_size => head_end,
+                None if self.read_buf.len() <= limits.max_head_size => return Ok(()),
+                _ => {
+                    error!("Rejecting request: head larger than {} bytes", limits.max_head_size);
+                    self.reject(StatusCode::RequestHeaderFieldsTooLarge);
+                    return Ok(());
+                }
+            };
+
+            // The head is parsed again once the body is complete, a chunked
+            // body is drained from read_buf while it arrives
+            let framing = match parse_request_before_body(&self.read_buf[..head_end]) {
+                Ok((_, headers)) => body_framing(&headers),
+                Err(e) => {
+                    error!("Parsing Error! {}", e);
+                    self.reject(StatusCode::BadRequest);
+                    return Ok(());
+                }
+            };
+            let framing = match framing {
+                Ok(framing) => framing,
+                Err(e) => {
+                    error!("Rejecting request: {}", e.message());
+                    self.reject(e.status_code());
+                    return Ok(());
+                }
+            };
+
+            let request_length = match framing {
+                BodyFraming::None => head_end,
+                BodyFraming::Length(size) if size > limits.max_body_size => {
+                    error!("Rejecting request: body larger than {} bytes", limits.max_body_size);
+                    self.reject(StatusCode::PayloadTooLarge);
+                    return Ok(());
+                }
+                BodyFraming::Length(size) => {
+                    if self.read_buf.len() < head_end + size {
+                        return Ok(());
+                    }
+                    head_end + size
+                }
+                BodyFraming::Chunked => {
+                    let chunked = self
+                        .chunked_body
+                        .get_or_insert_with(|| ChunkedBody::new(limits));
+                    match chunked.decoder.decode(&self.read_buf[head_end..], &mut chunked.body) {
+                        Ok(consumed) => {
+                            self.read_buf.drain(head_end..head_end + consumed);
+                        }
+                        Err(e) => {
+                            error!("Error in reading body: {}", e.message());
+                            self.reject(e.status_code());
+                            return Ok(());
+                        }
+                    }
+                    if !chunked.decoder.is_done() {
+                        return Ok(());
+                    }
+                    head_end
+                }
+            };
+
+            let (request_line, headers) = parse_request_before_body(&self.read_buf[..head_end])?;
+            let (body, trailers) = match &self.chunked_body {
+                Some(chunked) => match chunked.decoder.trailers() {
+                    Ok(trailers) => (&chunked.body[..], trailers),
+                    Err(e) => {
+                        error!("Parsing Error! {}", e);
+                        self.reject(StatusCode::BadRequest);
+                        return Ok(());
+                    }
+                },
+                None => (&self.read_buf[head_end..request_length], Headers::new()),
+            };
+
+            debug!(
+                "\n{:?} {:} {:?}\n{:?}\n{:}",
+                request_line.method,
+                request_line.path,
+                request_line.protocol,
+                HeaderDebugWrapper {
+                    headers: headers.clone()
+                },
+                String::from_utf8_lossy(body),
+            );
+
+            let mut request = Request::new(request_line, headers, body, trailers);
+
+            let response = manager.handle_request(&mut request);
+
+            // Checked after the handler, a shutdown may have started meanwhile
+            self.served += 1;
+            let keep_connection =
+                wants_keep_alive(&request.request_line.protocol, &request.headers)
+                    && self.served < keep_alive.max_requests
+                    && !shutdown::requested();
+            let protocol = &request.request_line.protocol;
+            let keep_connection =
+                queue_response(&mut self.pending, response, protocol, keep_connection);
+            self.close_after_write = !keep_connection;
+
+            self.chunked_body = None;
+            self.read_buf.drain(..request_length);
+        }
+
+        Ok(())
+    }
+
+    // Answers with status_code and closes the connection afterwards, as
+    // where the rejected request ends is unknown
+    fn reject(&mut self, status_code: StatusCode) {
+        let response = error_response(status_code);
+        queue_response(&mut self.pending, response, &HttpProtocol::HTTP1_1, false);
+        self.close_after_write = true;
+    }
+
+    fn is_between_requests(&self) -> bool {
+        self.read_buf.is_empty() && self.write_buf.is_empty() && self.pending.is_empty()
+    }
+
+    fn is_idle(&self, now: Instant, timeout: Duration) -> bool {
+        self.write_buf.is_empty()
+            && self.pending.is_empty()
+            && now.duration_since(self.last_active) >= timeout
+    }
+
+    fn wanted_interest(&self) -> u32 {
+        if self.peer_closed {
+            libc::EPOLLOUT as u32
+        } else if self.write_buf.is_empty() && self.pending.is_empty() && !self.stream.wants_write() {
+            READ_INTEREST
+        } else {
+            WRITE_INTEREST
+        }
+    }
+}
+
+// Single threaded reactor. Connections are non-blocking and registered to
+// epoll; requests are parsed as bytes arrive and responses are written as
+// the socket becomes writable. Handlers run on the reactor thread, so a slow
+// handler delays every other connection.
+pub struct EventLoop {
+    epoll: Epoll,
+    // Emptied when shutting down
+    listeners: Vec<Listener>,
+    manager: Arc<ApiEndPointManager>,
+    keep_alive: KeepAliveConfig,
+    limits: LimitsConfig,
+    shutdown_timeout: Duration,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+    last_idle_check: Instant,
+    // Set once shutting down, connections still open then are cut off
+    drain_deadline: Option<Instant>,
+}
+
+impl EventLoop {
+    pub fn new(
+        listeners: Vec<Listener>,
+        manager: Arc<ApiEndPointManager>,
+        keep_alive: KeepAliveConfig,
+        limits: LimitsConfig,
+        shutdown_timeout: Duration,
+    ) -> io::Result<Self> {
+        let epoll = Epoll::new()?;
+        for (index, listener) in listeners.iter().enumerate() {
+            listener.set_nonblocking(true)?;
+            let token = FIRST_LISTENER_TOKEN + index as u64;
+            epoll.add(listener.as_raw_fd(), token, libc::EPOLLIN as u32)?;
+        }
+        if shutdown::wake_fd() >= 0 {
+            epoll.add(shutdown::wake_fd(), SHUTDOWN_TOKEN, libc::EPOLLIN as u32)?;
+        }
+
+        Ok(Self {
+            epoll,
+            listeners,
+            manager,
+            keep_alive,
+            limits,
+            shutdown_timeout,
+            connections: HashMap::new(),
+            next_token: 0,
+            last_idle_check: Instant::now(),
+            drain_deadline: None,
+        })
+    }
+
+    // Runs until a requested shutdown has drained every connection
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        loop {
+            let timeout_ms = IDLE_CHECK_INTERVAL.as_millis() as i32;
+            let ready = self.epoll.wait(&mut events, timeout_ms)?;
+            for event in &events[..ready] {
+                let token = event.u64;
+                let flags = event.events;
+                match token {
+                    SHUTDOWN_TOKEN => self.begin_shutdown(),
+                    FIRST_LISTENER_TOKEN.. => {
+                        self.accept_connections((token - FIRST_LISTENER_TOKEN) as usize)
+                    }
+                    _ => self.handle_event(token, flags),
+                }
+            }
+
+            if shutdown::requested() {
+                self.begin_shutdown();
+            }
+
+            if let Some(deadline) = self.drain_deadline {
+                if self.connections.is_empty() {
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    warn!(
+                        "Shutdown deadline passed with {} open connections",
+                        self.connections.len()
+                    );
+                    return Ok(());
+                }
+            }
+
+            if self.last_idle_check.elapsed() >= IDLE_CHECK_INTERVAL {
+                self.close_idle_connections();
+            }
+        }
+    }
+
+    // Stops accepting and closes connections waiting for their next request.
+    // The others are closed once their response has been written.
+    fn begin_shutdown(&mut self) {
+        if self.drain_deadline.is_some() {
+            return;
+        }
+        info!("Shutdown requested, no longer accepting connections");
+        self.drain_deadline = Some(Instant::now() + self.shutdown_timeout);
+
+        let _ = self.epoll.delete(shutdown::wake_fd());
+        for listener in self.listeners.drain(..) {
+            let _ = self.epoll.delete(listener.as_raw_fd());
+        }
+
+        let idle: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.is_between_requests())
+            .map(|(&token, _)| token)
+            .collect();
+        for token in idle {
+            trace!("Closing idle connection {} for shutdown", token);
+            self.close_connection(token);
+        }
+    }
+
+    fn close_idle_connections(&mut self) {
+        let now = Instant::now();
+        self.last_idle_check = now;
+
+        let idle: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.is_idle(now, self.keep_alive.timeout))
+            .map(|(&token, _)| token)
+            .collect();
+        for token in idle {
+            trace!("Closing idle connection {}", token);
+            self.close_connection(token);
+        }
+    }
+
+    fn accept_connections(&mut self, index: usize) {
+        let listener = match self.listeners.get(index) {
+            Some(listener) => listener,
+            None => return,
+        };
+        loop {
+            let stream = match listener.accept() {
+                Ok(stream) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Connection Failed, {}", e);
+                    break;
+                }
+            };
+
+            if let Err(e) = stream.tcp().set_nonblocking(true) {
+                error!("Error in setting connection non-blocking: {}", e);
+                continue;
+            }
+
+            let token = self.next_token;
+            self.next_token += 1;
+            if let Err(e) = self.epoll.add(stream.as_raw_fd(), token, READ_INTEREST) {
+                error!("Error in registering connection: {}", e);
+                continue;
+            }
+            trace!("Accepted connection {}", token);
+            self.connections.insert(token, Connection::new(stream));
+        }
+    }
+
+    fn handle_event(&mut self, token: u64, flags: u32) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let mut open = flags & libc::EPOLLERR as u32 == 0;
+        if open && flags & (libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLHUP) as u32 != 0 {
+            open = connection.on_readable(&self.manager, &self.keep_alive, &self.limits);
+        }
+        if open && flags & libc::EPOLLOUT as u32 != 0 {
+            open = connection.on_writable();
+        }
+
+        if open {
+            let interest = connection.wanted_interest();
+            if interest != connection.interest {
+                let fd = connection.stream.as_raw_fd();
+                match self.epoll.modify(fd, token, interest) {
+                    Ok(()) => connection.interest = interest,
+                    Err(e) => {
+                        error!("Error in updating connection interest: {}", e);
+                        open = false;
+                    }
+                }
+            }
+        }
+
+        if !open {
+            self.close_connection(token);
+        }
+    }
+
+    fn close_connection(&mut self, token: u64) {
+        if let Some(connection) = self.connections.remove(&token) {
+            trace!("Closing connection {}", token);
+            let _ = self.epoll.delete(connection.stream.as_raw_fd());
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    any::{self, Any, TypeId},
+    collections::HashMap,
+    sync::Arc,
+};
+
+use super::{
+    request::{HttpMethod, Request},
+    response::Response,
+};
+
+// Anything answering requests. Closures taking &Request are handlers, so they
+// can capture what they need; the server calls them from many threads.
+pub trait Handler: Send + Sync {
+    fn call(&self, request: &Request) -> Response;
+
+    // Shown in the route table
+    fn name(&self) -> &'static str {
+        any::type_name::<Self>()
+    }
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn call(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+// A handler which knows the route it serves, as generated by the #[get],
+// #[post], #[put] and #[delete] attributes
+pub trait Route: Handler {
+    fn method(&self) -> HttpMethod;
+    fn pattern(&self) -> &'static str;
+}
+
+// Values shared by every handler, one per type. Registered on the
+// ApiEndPointManager and read with Request::state.
+#[derive(Default, Clone)]
+pub struct AppState {
+    values: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl AppState {
+    // Replaces an earlier value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: Arc<T>) {
+        self.values.insert(TypeId::of::<T>(), value);
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let value = self.values.get(&TypeId::of::<T>())?;
+        Arc::clone(value).downcast::<T>().ok()
+    }
+}
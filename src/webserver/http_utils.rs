@@ -1,3 +1,5 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 #[derive(Debug)]
 pub enum HttpProtocol {
     HTTP1_0,
@@ -29,3 +31,252 @@ impl HttpProtocol {
         }
     }
 }
+
+pub fn byte_slice_to_i32(bytes_slice: &[u8]) -> Result<i32, &'static str> {
+    let mut total: i32 = 0;
+    for &byte in bytes_slice {
+        if byte.is_ascii_digit() {
+            let cur = (byte - b'0') as i32;
+            total = total
+                .checked_mul(10)
+                .and_then(|total| total.checked_add(cur))
+                .ok_or("Error in parsing byte slice to i32")?;
+        } else {
+            return Err("Error in parsing byte slice to i32");
+        }
+    }
+    Ok(total)
+}
+
+// Decodes %XX escapes, fails on a `%` not followed by two hex digits
+pub fn percent_decode(input: &str) -> Result<Vec<u8>, &'static str> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3).ok_or("Truncated percent-encoding")?;
+            let hex = std::str::from_utf8(hex).map_err(|_| "Invalid percent-encoding")?;
+            let byte = u8::from_str_radix(hex, 16).map_err(|_| "Invalid percent-encoding")?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(decoded)
+}
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+// The four digits of the date formats
+const MAX_YEAR: i64 = 9999;
+
+// IMF-fixdate, as in `Sun, 06 Nov 1994 08:49:37 GMT`. Times before the epoch
+// are written as the epoch.
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        // 1970-01-01 was a Thursday
+        WEEKDAYS[((days + 4) % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+    )
+}
+
+// Accepts IMF-fixdate and the obsolete RFC 850 and asctime formats
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let (day, month, year, time) = match value.split_once(',') {
+        Some((_, rest)) => {
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            match fields[..] {
+                // 06 Nov 1994 08:49:37 GMT
+                [day, month, year, time, "GMT"] => (day, month, year.parse().ok()?, time),
+                // 06-Nov-94 08:49:37 GMT
+                [date, time, "GMT"] => {
+                    let mut date = date.split('-');
+                    let (day, month, year) = (date.next()?, date.next()?, date.next()?);
+                    if year.len() != 2 || date.next().is_some() {
+                        return None;
+                    }
+                    let year: i64 = year.parse().ok()?;
+                    let year = if year < 70 { 2000 + year } else { 1900 + year };
+                    (day, month, year, time)
+                }
+                _ => return None,
+            }
+        }
+        None => {
+            // Sun Nov  6 08:49:37 1994
+            let fields: Vec<&str> = value.split_whitespace().collect();
+            match fields[..] {
+                [_, month, day, time, year] => (day, month, year.parse().ok()?, time),
+                _ => return None,
+            }
+        }
+    };
+
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|&name| name == month)? as u32 + 1;
+    let mut time = time.split(':').map(|field| field.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if time.next().is_some() || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60
+    {
+        return None;
+    }
+    // Dates come from clients, a huge year must not overflow the arithmetic
+    if year > MAX_YEAR {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days
+        .checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian date and back, after
+// Howard Hinnant's chrono-compatible algorithms
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_index + 2) / 5 + 1) as u32;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Items of a header such as Accept or Accept-Encoding with their q-values,
+// 1 when absent. Parameters other than q are dropped, items with an invalid
+// q-value are skipped.
+pub fn quality_values(header: &str) -> Vec<(&str, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let value = parts.next().filter(|value| !value.is_empty())?;
+            let mut quality = 1.0;
+            for param in parts {
+                if let Some((name, q)) = param.split_once('=') {
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        quality = q.trim().parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q))?;
+                    }
+                }
+            }
+            Some((value, quality))
+        })
+        .collect()
+}
+
+// Escapes everything but unreserved characters and `/`, for putting a
+// decoded path back into a URL
+pub fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for &byte in path.as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sun, 06 Nov 1994 08:49:37 GMT
+    const EXAMPLE: u64 = 784_111_777;
+
+    fn secs(value: &str) -> Option<u64> {
+        parse_http_date(value).map(|time| time.duration_since(UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    #[test]
+    fn parse_http_date_formats() {
+        assert_eq!(secs("Sun, 06 Nov 1994 08:49:37 GMT"), Some(EXAMPLE));
+        assert_eq!(secs("Sunday, 06-Nov-94 08:49:37 GMT"), Some(EXAMPLE));
+        assert_eq!(secs("Sun Nov  6 08:49:37 1994"), Some(EXAMPLE));
+    }
+
+    #[test]
+    fn parse_http_date_two_digit_years() {
+        assert_eq!(secs("Thursday, 01-Jan-70 00:00:00 GMT"), Some(0));
+        assert_eq!(secs("Friday, 01-Jan-99 00:00:00 GMT"), Some(915_148_800));
+        assert_eq!(secs("Monday, 01-Jan-01 00:00:00 GMT"), Some(978_307_200));
+    }
+
+    #[test]
+    fn parse_http_date_leap_day() {
+        assert_eq!(secs("Thu, 29 Feb 2024 12:00:00 GMT"), Some(1_709_208_000));
+    }
+
+    #[test]
+    fn parse_http_date_last_year() {
+        assert_eq!(secs("Fri, 31 Dec 9999 23:59:59 GMT"), Some(253_402_300_799));
+    }
+
+    #[test]
+    fn parse_http_date_rejects_invalid() {
+        for value in [
+            "",
+            "yesterday",
+            "Sun, 06 Nov 1994 08:49:37 UTC",
+            "Sun, 06 Nov 1994 08:49 GMT",
+            "Sun, 06 Nov 1994 08:49:37:00 GMT",
+            "Sun, 06 nov 1994 08:49:37 GMT",
+            "Sun, 32 Nov 1994 08:49:37 GMT",
+            "Sun, 00 Nov 1994 08:49:37 GMT",
+            "Sun, 06 Nov 1994 24:00:00 GMT",
+            "Sun, 06 Nov 1969 08:49:37 GMT",
+            "Sunday, 06-Nov-1994 08:49:37 GMT",
+            "Sun Nov  6 08:49:37",
+            // Would overflow the seconds since the epoch
+            "Sun, 06 Nov 300000000000 08:49:37 GMT",
+            "Sun, 06 Nov 9223372036854775807 08:49:37 GMT",
+            "Sun Nov  6 08:49:37 300000000000",
+            "Sun, 01 Jan 10000 00:00:00 GMT",
+        ] {
+            assert_eq!(secs(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn format_http_date_round_trips() {
+        for secs_since_epoch in [0, EXAMPLE, 1_000_000_000, 1_709_208_000] {
+            let time = UNIX_EPOCH + Duration::from_secs(secs_since_epoch);
+            let formatted = format_http_date(time);
+            assert_eq!(secs(&formatted), Some(secs_since_epoch), "{}", formatted);
+        }
+        let example = format_http_date(UNIX_EPOCH + Duration::from_secs(EXAMPLE));
+        assert_eq!(example, "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+}
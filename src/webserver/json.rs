@@ -0,0 +1,32 @@
+use std::fmt::Write;
+
+// Appends values as a JSON array of strings
+pub fn push_list(json: &mut String, values: &[impl AsRef<str>]) {
+    json.push('[');
+    for (i, value) in values.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        push_string(json, value.as_ref());
+    }
+    json.push(']');
+}
+
+// Appends value as a JSON string literal
+pub fn push_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
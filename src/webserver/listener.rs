@@ -0,0 +1,126 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, ToSocketAddrs},
+    os::fd::{AsRawFd, RawFd},
+};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+use log::{info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use super::{config::ListenConfig, stream::Stream};
+
+const BACKLOG: i32 = 128;
+
+pub struct Listener {
+    tcp: TcpListener,
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Listener {
+    // The TLS handshake happens on the first read or write of the stream
+    pub fn accept(&self) -> io::Result<Stream> {
+        let (stream, _) = self.tcp.accept()?;
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let connection = rustls::ServerConnection::new(Arc::clone(config))
+                .map_err(io::Error::other)?;
+            let stream = rustls::StreamOwned::new(connection, stream);
+            return Ok(Stream::Tls(Box::new(stream)));
+        }
+        Ok(Stream::Plain(stream))
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.tcp.set_nonblocking(nonblocking)
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.tcp.as_raw_fd()
+    }
+}
+
+// Binds every configured address. A name resolving to several addresses is
+// bound to the first one that works, like TcpListener::bind.
+pub fn bind_all(configs: &[ListenConfig]) -> io::Result<Vec<Listener>> {
+    let resolved = configs
+        .iter()
+        .map(|config| {
+            let address = &config.address;
+            address
+                .to_socket_addrs()
+                .map(|addrs| addrs.collect::<Vec<_>>())
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", address, e)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // `[::]` also takes IPv4 connections unless IPv4 is listed separately
+    // on the same port, that bind would fail otherwise
+    let ipv4_ports: Vec<u16> = resolved
+        .iter()
+        .flatten()
+        .filter(|addr| addr.is_ipv4())
+        .map(|addr| addr.port())
+        .collect();
+
+    let mut listeners = Vec::with_capacity(configs.len());
+    for (config, candidates) in configs.iter().zip(resolved) {
+        let address = &config.address;
+        #[cfg(feature = "tls")]
+        let tls = config
+            .tls
+            .as_ref()
+            .map(super::tls::server_config)
+            .transpose()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", address, e)))?;
+
+        let mut last_error = Some(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: no address found", address),
+        ));
+        for addr in candidates {
+            let dual_stack = addr.ip().is_unspecified() && !ipv4_ports.contains(&addr.port());
+            match bind(addr, dual_stack) {
+                Ok(tcp) => {
+                    info!(
+                        "Listening on {}{}{}",
+                        addr,
+                        if addr.is_ipv6() && dual_stack { " (dual-stack)" } else { "" },
+                        if config.tls.is_some() { " with TLS" } else { "" },
+                    );
+                    listeners.push(Listener {
+                        tcp,
+                        #[cfg(feature = "tls")]
+                        tls: tls.clone(),
+                    });
+                    last_error = None;
+                    break;
+                }
+                Err(e) => {
+                    warn!("Cannot bind {} for {}: {}", addr, address, e);
+                    last_error = Some(io::Error::new(e.kind(), format!("{}: {}", address, e)));
+                }
+            }
+        }
+        if let Some(e) = last_error {
+            return Err(e);
+        }
+    }
+    Ok(listeners)
+}
+
+fn bind(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Same as TcpListener::bind, restarting doesn't wait for TIME_WAIT
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
@@ -0,0 +1,80 @@
+use std::{any, sync::Arc};
+
+use log::info;
+
+use super::{request::Request, response::Response};
+
+// Code running around endpoint dispatch. Global middleware wraps routing,
+// so it also sees 404 and 405 responses; route middleware wraps the handler
+// of its endpoint, with the path parameters already set.
+//
+// Middleware runs as layers: the before hooks in registration order, then the
+// after hooks in reverse. A before hook returning a response skips the inner
+// layers, the after hooks of the layers that ran still see it.
+pub trait Middleware: Send + Sync {
+    fn before(&self, _request: &mut Request) -> Option<Response> {
+        None
+    }
+
+    fn after(&self, _request: &Request, response: Response) -> Response {
+        response
+    }
+
+    // Shown in the route table
+    fn name(&self) -> &'static str {
+        any::type_name::<Self>()
+    }
+}
+
+pub fn run_layers(
+    layers: &[Arc<dyn Middleware>],
+    request: &mut Request,
+    inner: impl FnOnce(&mut Request) -> Response,
+) -> Response {
+    let mut ran = 0;
+    let mut response = None;
+    for layer in layers {
+        ran += 1;
+        response = layer.before(request);
+        if response.is_some() {
+            break;
+        }
+    }
+
+    let mut response = match response {
+        Some(response) => response,
+        None => inner(request),
+    };
+    for layer in layers[..ran].iter().rev() {
+        response = layer.after(request, response);
+    }
+    response
+}
+
+// Logs every request with the status it was answered with
+pub struct AccessLog;
+
+impl Middleware for AccessLog {
+    fn after(&self, request: &Request, response: Response) -> Response {
+        info!(
+            "{} {} {}",
+            request.request_line.method.as_str(),
+            request.request_line.path,
+            String::from_utf8_lossy(response.status_code().value()),
+        );
+        response
+    }
+}
+
+// Sets a header on every response
+pub struct SetHeader {
+    pub name: &'static str,
+    pub value: &'static str,
+}
+
+impl Middleware for SetHeader {
+    fn after(&self, _request: &Request, mut response: Response) -> Response {
+        response.set_header(self.name, self.value);
+        response
+    }
+}
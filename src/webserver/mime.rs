@@ -0,0 +1,119 @@
+use std::{collections::HashMap, path::Path};
+
+// Files with an unknown or no extension
+const DEFAULT_TYPE: &str = "application/octet-stream";
+
+const TYPES: &[(&str, &str)] = &[
+    // Web
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("xml", "application/xml"),
+    ("wasm", "application/wasm"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("md", "text/markdown"),
+    ("ics", "text/calendar"),
+    ("vtt", "text/vtt"),
+    ("pdf", "application/pdf"),
+    // Fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("eot", "application/vnd.ms-fontobject"),
+    // Images
+    ("png", "image/png"),
+    ("apng", "image/apng"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("bmp", "image/bmp"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    // Audio and video
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("aac", "audio/aac"),
+    ("m4a", "audio/mp4"),
+    ("weba", "audio/webm"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+    ("avi", "video/x-msvideo"),
+    ("mpeg", "video/mpeg"),
+    // Archives
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("7z", "application/x-7z-compressed"),
+    ("rar", "application/vnd.rar"),
+];
+
+// Content-Type of static files by their extension
+pub struct MimeTypes {
+    by_extension: HashMap<String, String>,
+    // Added to textual types, none when empty
+    charset: String,
+}
+
+impl MimeTypes {
+    // The built-in table with overrides applied, extensions are matched
+    // case-insensitively
+    pub fn new(overrides: &HashMap<String, String>, charset: &str) -> Self {
+        let mut by_extension: HashMap<String, String> = TYPES
+            .iter()
+            .map(|&(extension, mime)| (extension.to_string(), mime.to_string()))
+            .collect();
+        for (extension, mime) in overrides {
+            let extension = extension.trim_start_matches('.').to_ascii_lowercase();
+            by_extension.insert(extension, mime.clone());
+        }
+        Self {
+            by_extension,
+            charset: charset.to_string(),
+        }
+    }
+
+    pub fn content_type(&self, path: &Path) -> String {
+        let mime = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| self.by_extension.get(&extension.to_ascii_lowercase()))
+            .map_or(DEFAULT_TYPE, String::as_str);
+
+        if self.charset.is_empty() || mime.contains("charset=") || !is_text(mime) {
+            return mime.to_string();
+        }
+        format!("{}; charset={}", mime, self.charset)
+    }
+}
+
+fn is_text(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || essence == "application/json"
+        || essence == "application/xml"
+        || essence == "application/javascript"
+}
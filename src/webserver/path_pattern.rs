@@ -0,0 +1,189 @@
+use std::fmt;
+
+// One `/`-separated piece of a route pattern
+#[derive(Debug, Clone)]
+pub enum Segment {
+    Static(String),
+    // `:name`, matches one non-empty segment
+    Param(String),
+    // `*name` or `*`, matches the rest of the path, possibly empty
+    Wildcard(String),
+}
+
+impl Segment {
+    // Routing priority, lower is tried first
+    fn rank(&self) -> u8 {
+        match self {
+            Segment::Static(_) => 0,
+            Segment::Param(_) => 1,
+            Segment::Wildcard(_) => 2,
+        }
+    }
+}
+
+// A parsed route pattern such as `/users/:id/posts/:post_id` or
+// `/files/*rest`
+#[derive(Debug, Clone)]
+pub struct PathPattern {
+    pattern: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug)]
+pub enum RouteError {
+    InvalidPattern(String, &'static str),
+    // A new route would match exactly the same requests as an existing one
+    Ambiguous { pattern: String, existing: String },
+}
+
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteError::InvalidPattern(pattern, message) => {
+                write!(f, "Invalid route pattern `{}`: {}", pattern, message)
+            }
+            RouteError::Ambiguous { pattern, existing } => write!(
+                f,
+                "Route `{}` is ambiguous with the registered route `{}`",
+                pattern, existing
+            ),
+        }
+    }
+}
+
+impl PathPattern {
+    pub fn parse(pattern: &str) -> Result<Self, RouteError> {
+        let invalid = |message| RouteError::InvalidPattern(pattern.to_string(), message);
+
+        let rest = pattern.strip_prefix('/').ok_or(invalid("must start with `/`"))?;
+        let mut segments = Vec::new();
+        let mut names: Vec<String> = Vec::new();
+        let pieces: Vec<&str> = rest.split('/').collect();
+        for (i, piece) in pieces.iter().enumerate() {
+            let segment = if let Some(name) = piece.strip_prefix(':') {
+                if name.is_empty() {
+                    return Err(invalid("parameter without a name"));
+                }
+                Segment::Param(name.to_string())
+            } else if let Some(name) = piece.strip_prefix('*') {
+                if i != pieces.len() - 1 {
+                    return Err(invalid("wildcard must be the last segment"));
+                }
+                Segment::Wildcard(name.to_string())
+            } else {
+                if piece.contains(['*', ':']) {
+                    return Err(invalid("`:` and `*` must start a segment"));
+                }
+                Segment::Static(piece.to_string())
+            };
+
+            if let Segment::Param(name) | Segment::Wildcard(name) = &segment {
+                if !name.is_empty() && names.contains(name) {
+                    return Err(invalid("parameter name used twice"));
+                }
+                names.push(name.clone());
+            }
+            segments.push(segment);
+        }
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            segments,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    // Whether some path is matched by both patterns and routed to self,
+    // because self is more specific where their shapes first differ.
+    // Patterns of the same shape never shadow each other.
+    pub fn shadows(&self, other: &PathPattern) -> bool {
+        let mut preferred = None;
+        for (mine, theirs) in self.segments.iter().zip(&other.segments) {
+            match (mine, theirs) {
+                (Segment::Static(a), Segment::Static(b)) if a != b => return false,
+                // A parameter doesn't match an empty segment
+                (Segment::Static(text), Segment::Param(_))
+                | (Segment::Param(_), Segment::Static(text))
+                    if text.is_empty() =>
+                {
+                    return false
+                }
+                _ => {}
+            }
+            if preferred.is_none() && mine.rank() != theirs.rank() {
+                preferred = Some(mine.rank() < theirs.rank());
+            }
+            // Both match whatever follows, a wildcard also matching nothing
+            if matches!(mine, Segment::Wildcard(_)) || matches!(theirs, Segment::Wildcard(_)) {
+                return preferred.unwrap_or(false);
+            }
+        }
+        self.segments.len() == other.segments.len() && preferred.unwrap_or(false)
+    }
+
+    // Names of the parameters and the wildcard in pattern order, empty for
+    // an unnamed wildcard
+    pub fn param_names(&self) -> impl Iterator<Item = &str> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Param(name) | Segment::Wildcard(name) => Some(name.as_str()),
+            Segment::Static(_) => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shadows(pattern: &str, other: &str) -> bool {
+        let pattern = PathPattern::parse(pattern).unwrap();
+        let other = PathPattern::parse(other).unwrap();
+        pattern.shadows(&other)
+    }
+
+    #[test]
+    fn static_shadows_param() {
+        assert!(shadows("/users/me", "/users/:id"));
+        assert!(!shadows("/users/:id", "/users/me"));
+    }
+
+    #[test]
+    fn param_shadows_wildcard() {
+        assert!(shadows("/files/:name", "/files/*rest"));
+        assert!(!shadows("/files/*rest", "/files/:name"));
+        // Matches /files/x/edit, which the wildcard matches too
+        assert!(shadows("/files/:name/edit", "/files/*"));
+        assert!(shadows("/files/readme", "/files/*"));
+    }
+
+    #[test]
+    fn first_difference_decides() {
+        assert!(shadows("/a/:x/c", "/a/:x/:y"));
+        assert!(shadows("/a/b/:y", "/a/:x/c"));
+        assert!(!shadows("/a/:x/c", "/a/b/:y"));
+    }
+
+    #[test]
+    fn disjoint_patterns_dont_shadow() {
+        assert!(!shadows("/a/:x", "/b/:y"));
+        assert!(!shadows("/users/me", "/users/:id/posts"));
+        assert!(!shadows("/a/b", "/a/:x/c"));
+        // A parameter doesn't match the empty segment after a trailing `/`
+        assert!(!shadows("/users/", "/users/:id"));
+        assert!(!shadows("/users/:id", "/users/"));
+    }
+
+    #[test]
+    fn same_shape_doesnt_shadow() {
+        assert!(!shadows("/users/:id", "/users/:name"));
+        assert!(!shadows("/files/*a", "/files/*b"));
+        assert!(!shadows("/a/b", "/a/b"));
+    }
+}
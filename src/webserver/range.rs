@@ -0,0 +1,262 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// More ranges than this in one request are answered with the whole file,
+// so a request for many tiny pieces can't multiply the response overhead
+const MAX_RANGES: usize = 16;
+
+// Byte range of a file, both ends inclusive
+#[derive(Clone, Copy, Debug)]
+pub struct ByteRange {
+    pub first: u64,
+    pub last: u64,
+}
+
+impl ByteRange {
+    pub fn length(&self) -> u64 {
+        self.last - self.first + 1
+    }
+
+    pub fn content_range(&self, length: u64) -> String {
+        format!("bytes {}-{}/{}", self.first, self.last, length)
+    }
+}
+
+// Ranges of a `Range: bytes=...` header which lie in a file of length bytes,
+// sorted with overlapping and adjacent ones merged. None when the header
+// is to be ignored: a malformed value, another unit, or too many ranges. An
+// empty list means no range is satisfiable.
+pub fn satisfiable_ranges(header: &[u8], length: u64) -> Option<Vec<ByteRange>> {
+    let header = std::str::from_utf8(header).ok()?;
+    let (unit, specs) = header.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        count += 1;
+        if count > MAX_RANGES {
+            return None;
+        }
+
+        let (first, last) = spec.split_once('-')?;
+        let range = if first.is_empty() {
+            // The last n bytes
+            let suffix: u64 = parse_digits(last)?;
+            (suffix > 0 && length > 0).then(|| ByteRange {
+                first: length.saturating_sub(suffix),
+                last: length - 1,
+            })
+        } else {
+            let first = parse_digits(first)?;
+            let last = match last {
+                "" => u64::MAX,
+                last => parse_digits(last)?,
+            };
+            if last < first {
+                return None;
+            }
+            (first < length).then(|| ByteRange {
+                first,
+                last: last.min(length - 1),
+            })
+        };
+        ranges.extend(range);
+    }
+    if count == 0 {
+        return None;
+    }
+
+    ranges.sort_by_key(|range| range.first);
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(previous) if range.first <= previous.last.saturating_add(1) => {
+                previous.last = previous.last.max(range.last);
+            }
+            _ => merged.push(range),
+        }
+    }
+    Some(merged)
+}
+
+fn parse_digits(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+// Separates the parts of a multipart/byteranges body, unique enough not to
+// show up in the file
+pub fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.subsec_nanos());
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("byteranges{:08x}{:016x}", nanos, count)
+}
+
+enum Part {
+    Bytes(Cursor<Vec<u8>>),
+    Range { first: u64, remaining: u64, started: bool },
+}
+
+// Body of a multipart/byteranges response, reading each range from the file
+// when it's reached
+pub struct MultipartRanges {
+    file: File,
+    parts: VecDeque<Part>,
+    length: u64,
+}
+
+impl MultipartRanges {
+    pub fn new(
+        file: File,
+        ranges: &[ByteRange],
+        file_length: u64,
+        content_type: &str,
+        boundary: &str,
+    ) -> Self {
+        let mut parts = VecDeque::new();
+        let mut length = 0;
+        for range in ranges {
+            let head = format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                content_type,
+                range.content_range(file_length)
+            );
+            length += head.len() as u64 + range.length();
+            parts.push_back(Part::Bytes(Cursor::new(head.into_bytes())));
+            parts.push_back(Part::Range {
+                first: range.first,
+                remaining: range.length(),
+                started: false,
+            });
+        }
+        let end = format!("\r\n--{}--\r\n", boundary);
+        length += end.len() as u64;
+        parts.push_back(Part::Bytes(Cursor::new(end.into_bytes())));
+
+        Self {
+            file,
+            parts,
+            length,
+        }
+    }
+
+    // Length of the whole body
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+}
+
+impl Read for MultipartRanges {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            let read = match part {
+                Part::Bytes(bytes) => bytes.read(buf)?,
+                Part::Range {
+                    first,
+                    remaining,
+                    started,
+                } => {
+                    if !*started {
+                        self.file.seek(SeekFrom::Start(*first))?;
+                        *started = true;
+                    }
+                    let limit = buf.len().min(*remaining as usize);
+                    let read = self.file.read(&mut buf[..limit])?;
+                    if read == 0 && *remaining > 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "File shrank while it was served",
+                        ));
+                    }
+                    *remaining -= read as u64;
+                    read
+                }
+            };
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.parts.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(header: &str, length: u64) -> Option<Vec<(u64, u64)>> {
+        satisfiable_ranges(header.as_bytes(), length)
+            .map(|ranges| ranges.iter().map(|range| (range.first, range.last)).collect())
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(ranges("bytes=-10", 100), Some(vec![(90, 99)]));
+        // Longer than the file, the whole file
+        assert_eq!(ranges("bytes=-200", 100), Some(vec![(0, 99)]));
+        assert_eq!(ranges("bytes=-0", 100), Some(vec![]));
+        assert_eq!(ranges("bytes=-10", 0), Some(vec![]));
+    }
+
+    #[test]
+    fn bounded_and_open_ranges() {
+        assert_eq!(ranges("bytes=0-0", 100), Some(vec![(0, 0)]));
+        assert_eq!(ranges("bytes=90-", 100), Some(vec![(90, 99)]));
+        assert_eq!(ranges("bytes=90-200", 100), Some(vec![(90, 99)]));
+        assert_eq!(ranges("Bytes = 10-19", 100), Some(vec![(10, 19)]));
+        assert_eq!(ranges("bytes=100-", 100), Some(vec![]));
+        assert_eq!(ranges("bytes=100-200, 300-", 100), Some(vec![]));
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_merge() {
+        assert_eq!(ranges("bytes=0-9,5-19", 100), Some(vec![(0, 19)]));
+        assert_eq!(ranges("bytes=0-9,10-19", 100), Some(vec![(0, 19)]));
+        assert_eq!(ranges("bytes=10-19,0-49", 100), Some(vec![(0, 49)]));
+        assert_eq!(ranges("bytes=-10,80-", 100), Some(vec![(80, 99)]));
+        assert_eq!(ranges("bytes=50-59, 0-9, 100-", 100), Some(vec![(0, 9), (50, 59)]));
+    }
+
+    #[test]
+    fn malformed_headers_are_ignored() {
+        for header in [
+            "bytes=",
+            "bytes=,",
+            "bytes=5",
+            "bytes=5-2",
+            "bytes=a-b",
+            "bytes=+1-2",
+            "bytes=--5",
+            "items=0-9",
+            "0-9",
+        ] {
+            assert_eq!(ranges(header, 100), None, "{}", header);
+        }
+    }
+
+    #[test]
+    fn too_many_ranges_are_ignored() {
+        let specs = |count: u64| {
+            let specs: Vec<String> = (0..count).map(|i| format!("{0}-{0}", i * 2)).collect();
+            format!("bytes={}", specs.join(","))
+        };
+        let most = ranges(&specs(MAX_RANGES as u64), 100);
+        assert_eq!(most.map(|ranges| ranges.len()), Some(MAX_RANGES));
+        assert_eq!(ranges(&specs(MAX_RANGES as u64 + 1), 100), None);
+    }
+}
@@ -1,9 +1,14 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use log::trace;
 
-use super::http_utils::HttpProtocol;
+use super::{
+    handler::AppState,
+    http_utils::{byte_slice_to_i32, percent_decode, HttpProtocol},
+    response::StatusCode,
+};
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Debug)]
 pub enum HttpMethod {
     GET,
@@ -16,6 +21,21 @@ pub enum HttpMethod {
     CONNECT,
 }
 
+impl HttpMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::GET => "GET",
+            HttpMethod::POST => "POST",
+            HttpMethod::PUT => "PUT",
+            HttpMethod::DELETE => "DELETE",
+            HttpMethod::HEAD => "HEAD",
+            HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::TRACE => "TRACE",
+            HttpMethod::CONNECT => "CONNECT",
+        }
+    }
+}
+
 impl TryFrom<&[u8]> for HttpMethod {
     type Error = &'static str;
     fn try_from(method: &[u8]) -> Result<Self, Self::Error> {
@@ -35,74 +55,334 @@ impl TryFrom<&[u8]> for HttpMethod {
 
 pub struct RequestLine {
     pub method: HttpMethod,
+    // Request target up to the `?`
     pub path: String,
+    // Raw query after the `?`, if there was one
+    pub query: Option<String>,
     pub protocol: HttpProtocol,
 }
 
+pub type Headers<'buf> = HashMap<&'buf [u8], &'buf [u8]>;
+
 pub struct Request<'buf> {
     pub request_line: RequestLine,
-    pub headers: HashMap<&'buf [u8], &'buf [u8]>,
+    pub headers: Headers<'buf>,
     pub body: &'buf [u8],
+    // Trailer fields of a chunked body
+    #[allow(dead_code)]
+    pub trailers: Headers<'buf>,
+    // Decoded from request_line.query
+    pub query: Query,
+    // Filled in by the router from the matched route pattern
+    pub params: Params,
+    // Set by the ApiEndPointManager before calling the handler
+    pub app_state: Option<Arc<AppState>>,
+}
+
+impl<'buf> Request<'buf> {
+    pub fn new(
+        request_line: RequestLine,
+        headers: Headers<'buf>,
+        body: &'buf [u8],
+        trailers: Headers<'buf>,
+    ) -> Self {
+        let query = request_line
+            .query
+            .as_deref()
+            .map(Query::parse)
+            .unwrap_or_default();
+        Self {
+            request_line,
+            headers,
+            body,
+            trailers,
+            query,
+            params: Params::default(),
+            app_state: None,
+        }
+    }
+
+    // Value captured by `:name` or `*name` in the route pattern
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name)
+    }
+
+    // Shared value of type T registered with ApiEndPointManager::manage
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.app_state.as_ref()?.get::<T>()
+    }
+}
+
+// Query parameters decoded as application/x-www-form-urlencoded. A name may
+// be given several times, its values are kept in order.
+#[derive(Default, Debug)]
+pub struct Query(HashMap<String, Vec<String>>);
+
+#[allow(dead_code)]
+impl Query {
+    pub fn parse(query: &str) -> Self {
+        let mut values: HashMap<String, Vec<String>> = HashMap::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            values
+                .entry(decode_query_component(name))
+                .or_default()
+                .push(decode_query_component(value));
+        }
+        Self(values)
+    }
+
+    // First value of name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name)?.first().map(String::as_str)
+    }
+
+    pub fn get_all(&self, name: &str) -> &[String] {
+        self.0.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.0.iter().map(|(name, values)| (name.as_str(), values.as_slice()))
+    }
+}
+
+// `+` is a space, a malformed escape is kept as written
+fn decode_query_component(component: &str) -> String {
+    let component = component.replace('+', " ");
+    match percent_decode(&component) {
+        Ok(decoded) => String::from_utf8_lossy(&decoded).into_owned(),
+        Err(_) => component,
+    }
+}
+
+// Percent-decodes path, then drops `.` segments and repeated slashes and
+// resolves `..`. A trailing slash is kept. Fails on malformed escapes, on
+// NUL or non UTF-8 bytes, and on `..` above the root.
+pub fn normalize_path(path: &str) -> Result<String, &'static str> {
+    if !path.starts_with('/') {
+        return Err("Path must start with /");
+    }
+    let decoded = percent_decode(path)?;
+    let decoded = String::from_utf8(decoded).map_err(|_| "Path is not valid UTF-8")?;
+    if decoded.contains('\0') {
+        return Err("Path contains NUL");
+    }
+
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop().ok_or("Path escapes the root")?;
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+    let trailing_slash = ["/", "/.", "/.."].iter().any(|end| decoded.ends_with(end));
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+// Values captured from the path, in pattern order
+#[derive(Default, Debug)]
+pub struct Params(Vec<(String, String)>);
+
+#[allow(dead_code)]
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn push(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_string(), value.to_string()));
+    }
+}
+
+// Returns the length of the request head (request line, headers and the
+// empty line) once it is fully contained in buf
+pub fn find_head_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4)
+        .position(|s| s == b"\r\n\r\n")
+        .map(|i| i + 4)
 }
 
 // Parse status line, headers, and body
 pub fn parse_request_before_body(
-    request_buf: &Vec<u8>,
-) -> Result<(RequestLine, HashMap<&[u8], &[u8]>), &'static str> {
+    request_buf: &[u8],
+) -> Result<(RequestLine, Headers<'_>), &'static str> {
     trace!("Enter parse_request_before_body");
-    let crlf_position: Vec<usize> = request_buf
-        .windows(2)
-        .enumerate()
-        .filter(|(_, s)| s.starts_with(b"\r\n"))
-        .map(|(i, _)| i as usize)
-        .collect();
-
-    if crlf_position.len() <= 2 {
-        return Err("Something is wrong in request. CRLF <= 2 detected");
-    }
+    let line_end = find_crlf(request_buf).ok_or("Request line without CRLF")?;
 
     // extract request_line
-    let request_line = parse_request_line(&request_buf[0..crlf_position[0]])?;
+    let request_line = parse_request_line(&request_buf[..line_end])?;
 
     // extract headers
-    let mut headers: HashMap<&[u8], &[u8]> = HashMap::new();
-    let mut cur_start_position = crlf_position[0] + 2;
-    for &cur_crlf_position in &crlf_position[1..] {
+    let headers = parse_headers(&request_buf[line_end + 2..])?;
+
+    trace!("Leave parse_request_buf_before_body");
+
+    Ok((request_line, headers))
+}
+
+// Fields which decide where the body ends. A second one could be read
+// differently by a proxy in front of us, so it's refused.
+const FRAMING_HEADERS: [&str; 2] = ["Content-Length", "Transfer-Encoding"];
+
+// Parse header lines, each ending with CRLF, until an empty line or the end
+// of buf. Whitespace before the colon is refused (RFC 9112 section 5.1), as
+// is a repeated framing header.
+pub fn parse_headers(buf: &[u8]) -> Result<Headers<'_>, &'static str> {
+    let mut headers: Headers = HashMap::new();
+    let mut cur_start_position = 0;
+    while let Some(line_length) = find_crlf(&buf[cur_start_position..]) {
         // crlf two times => body
-        if cur_start_position == cur_crlf_position {
+        if line_length == 0 {
             break;
         }
 
-        let mut header_line =
-            request_buf[cur_start_position..cur_crlf_position].split(|&s| s == b':');
-        let header = header_line.next().unwrap();
-        let value = trim_byte_slice(header_line.next().unwrap());
+        let mut header_line = buf[cur_start_position..cur_start_position + line_length]
+            .splitn(2, |&s| s == b':');
+        let header = header_line.next().ok_or("Empty header line")?;
+        let value = trim_byte_slice(header_line.next().ok_or("Header without colon")?);
+        if header.is_empty() || header.iter().any(|c| c.is_ascii_whitespace()) {
+            return Err("Header name is empty or contains whitespace");
+        }
+        let framing = FRAMING_HEADERS
+            .iter()
+            .any(|name| header.eq_ignore_ascii_case(name.as_bytes()));
+        if framing && get_header(&headers, &String::from_utf8_lossy(header)).is_some() {
+            return Err("Repeated Content-Length or Transfer-Encoding");
+        }
         headers.insert(header, value);
 
-        cur_start_position = cur_crlf_position + 2;
+        cur_start_position += line_length + 2;
     }
 
-    trace!("Leave parse_request_buf_before_body");
+    Ok(headers)
+}
 
-    Ok((request_line, headers))
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|s| s == b"\r\n")
+}
+
+pub enum BodyFraming {
+    None,
+    Length(usize),
+    Chunked,
+}
+
+pub enum BodyFramingError {
+    ContentLengthWithTransferEncoding,
+    InvalidContentLength,
+    UnsupportedTransferEncoding,
+}
+
+impl BodyFramingError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            BodyFramingError::UnsupportedTransferEncoding => StatusCode::NotImplemented,
+            _ => StatusCode::BadRequest,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            BodyFramingError::ContentLengthWithTransferEncoding => {
+                "Both Content-Length and Transfer-Encoding are present"
+            }
+            BodyFramingError::InvalidContentLength => "Invalid Content-Length",
+            BodyFramingError::UnsupportedTransferEncoding => "Unsupported Transfer-Encoding",
+        }
+    }
+}
+
+// Determine how the body is delimited (RFC 9112 section 6.3).
+// A request carrying both Content-Length and Transfer-Encoding is rejected
+// rather than letting one win, as the two may be read differently by a proxy
+// in front of us. Chunked is the only supported transfer coding.
+pub fn body_framing(headers: &Headers) -> Result<BodyFraming, BodyFramingError> {
+    let content_length = get_header(headers, "Content-Length");
+    let transfer_encoding = get_header(headers, "Transfer-Encoding");
+
+    match (content_length, transfer_encoding) {
+        (Some(_), Some(_)) => Err(BodyFramingError::ContentLengthWithTransferEncoding),
+        (Some(size), None) => {
+            if size.is_empty() {
+                return Err(BodyFramingError::InvalidContentLength);
+            }
+            let size =
+                byte_slice_to_i32(size).map_err(|_| BodyFramingError::InvalidContentLength)?;
+            Ok(BodyFraming::Length(size as usize))
+        }
+        (None, Some(coding)) if coding.eq_ignore_ascii_case(b"chunked") => {
+            Ok(BodyFraming::Chunked)
+        }
+        (None, Some(_)) => Err(BodyFramingError::UnsupportedTransferEncoding),
+        (None, None) => Ok(BodyFraming::None),
+    }
+}
+
+// Header names are case-insensitive
+pub fn get_header<'buf>(headers: &Headers<'buf>, name: &str) -> Option<&'buf [u8]> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name.as_bytes()))
+        .map(|(_, &value)| value)
+}
+
+// HTTP/1.1 connections are persistent unless the client asks to close,
+// HTTP/1.0 connections only when the client asks to keep them alive
+pub fn wants_keep_alive(protocol: &HttpProtocol, headers: &Headers) -> bool {
+    let has_token = |token: &str| {
+        get_header(headers, "Connection").is_some_and(|value| {
+            value
+                .split(|&c| c == b',')
+                .any(|v| trim_byte_slice(v).eq_ignore_ascii_case(token.as_bytes()))
+        })
+    };
+
+    match protocol {
+        HttpProtocol::HTTP1_0 => has_token("keep-alive"),
+        _ => !has_token("close"),
+    }
 }
 
 fn parse_request_line(request_line: &[u8]) -> Result<RequestLine, &'static str> {
     let mut request_line = request_line.split(|c| c.is_ascii_whitespace());
-    let method = request_line.next().unwrap();
-    let path = request_line.next().unwrap();
-    let protocol = request_line.next().unwrap();
+    let method = request_line.next().ok_or("Request line without method")?;
+    let path = request_line.next().ok_or("Request line without path")?;
+    let protocol = request_line.next().ok_or("Request line without protocol")?;
 
     // convert method string to enum
     let method = HttpMethod::try_from(method)?;
-    let path =
+    let target =
         String::from_utf8(path.to_vec()).map_err(|_| "Error in converting path to string")?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target, None),
+    };
 
     let protocol = HttpProtocol::try_from(protocol)?;
 
     Ok(RequestLine {
         method,
         path,
+        query,
         protocol,
     })
 }
@@ -127,3 +407,45 @@ fn trim_byte_slice(byte_slice: &[u8]) -> &[u8] {
 
     &byte_slice[start..end]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_path_resolves_dot_segments() {
+        assert_eq!(normalize_path("/a/./b/../c").unwrap(), "/a/c");
+        assert_eq!(normalize_path("//a///b").unwrap(), "/a/b");
+        assert_eq!(normalize_path("/a/b/..").unwrap(), "/a/");
+        assert_eq!(normalize_path("/a/.").unwrap(), "/a/");
+        assert_eq!(normalize_path("/a/").unwrap(), "/a/");
+        assert_eq!(normalize_path("/..a/b..").unwrap(), "/..a/b..");
+        assert_eq!(normalize_path("/a/..").unwrap(), "/");
+        assert_eq!(normalize_path("/").unwrap(), "/");
+    }
+
+    #[test]
+    fn normalize_path_decodes_before_resolving() {
+        assert_eq!(normalize_path("/a%20b").unwrap(), "/a b");
+        assert_eq!(normalize_path("/a/%2e%2e/b").unwrap(), "/b");
+        assert_eq!(normalize_path("/a%2F..%2Fb").unwrap(), "/b");
+    }
+
+    #[test]
+    fn normalize_path_rejects_traversal() {
+        assert!(normalize_path("/..").is_err());
+        assert!(normalize_path("/a/../..").is_err());
+        assert!(normalize_path("/%2e%2e/etc/passwd").is_err());
+        assert!(normalize_path("/a/..%2F..%2Fetc").is_err());
+    }
+
+    #[test]
+    fn normalize_path_rejects_invalid_input() {
+        assert!(normalize_path("a/b").is_err());
+        assert!(normalize_path("").is_err());
+        assert!(normalize_path("/a%00b").is_err());
+        assert!(normalize_path("/a%ff").is_err());
+        assert!(normalize_path("/a%2").is_err());
+        assert!(normalize_path("/a%zz").is_err());
+    }
+}
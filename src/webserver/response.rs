@@ -1,4 +1,8 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    io::{self, ErrorKind, Read},
+    mem,
+};
 
 use super::http_utils::HttpProtocol;
 
@@ -75,6 +79,19 @@ pub enum StatusCode {
 }
 
 impl StatusCode {
+    // 1xx, 204 and 304 responses end after the head
+    pub fn allows_content(&self) -> bool {
+        !matches!(
+            self,
+            StatusCode::Continue
+                | StatusCode::SwitchingProtocol
+                | StatusCode::Processing
+                | StatusCode::EarlyHints
+                | StatusCode::NoContent
+                | StatusCode::NotModified
+        )
+    }
+
     pub fn value(&self) -> &'static [u8] {
         match self {
             StatusCode::Continue => b"100",
@@ -212,24 +229,178 @@ impl StatusCode {
     }
 }
 
+const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    Reader {
+        reader: Box<dyn Read + Send>,
+        length: Option<u64>,
+    },
+    Chunks(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+}
+
+impl Body {
+    // Length of the body if it is known before sending
+    pub fn length(&self) -> Option<u64> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { length, .. } => *length,
+            Body::Chunks(_) => None,
+        }
+    }
+
+    // Next piece of the body, None at the end
+    pub fn next_data(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Body::Bytes(bytes) if bytes.is_empty() => Ok(None),
+            Body::Bytes(bytes) => Ok(Some(mem::take(bytes))),
+            Body::Reader { reader, .. } => {
+                let mut buf = vec![0; STREAM_CHUNK_SIZE];
+                loop {
+                    match reader.read(&mut buf) {
+                        Ok(0) => return Ok(None),
+                        Ok(n) => {
+                            buf.truncate(n);
+                            return Ok(Some(buf));
+                        }
+                        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            // An empty chunk would end a chunked body early
+            Body::Chunks(chunks) => Ok(chunks.find(|chunk| !chunk.is_empty())),
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Bytes(Vec::new())
+    }
+}
+
+// How the end of the body is marked on the wire
+#[derive(Clone, Copy)]
+pub enum Framing {
+    Length(u64),
+    Chunked,
+    // Unknown length for an HTTP/1.0 client, the body ends when the
+    // connection is closed
+    UntilClose,
+}
+
+impl Framing {
+    pub fn closes_connection(&self) -> bool {
+        matches!(self, Framing::UntilClose)
+    }
+}
+
 pub struct Response {
     protocol: HttpProtocol,
     status_code: StatusCode,
     headers: HashMap<Vec<u8>, Vec<u8>>,
-    body: Vec<u8>,
+    body: Body,
+    // Only the head is sent, framed as if the body followed
+    head_only: bool,
 }
 
 impl Response {
     pub fn builder() -> ResponseBuilder {
-        return ResponseBuilder {
+        ResponseBuilder {
             protocol: None,
             status_code: None,
             headers: None,
             body: None,
+        }
+    }
+
+    pub fn status_code(&self) -> &StatusCode {
+        &self.status_code
+    }
+
+    // Replaces a header of the same name, whatever its case
+    pub fn set_header(&mut self, key: &str, val: &str) {
+        self.remove_header(key);
+        self.headers.insert(key.into(), val.into());
+    }
+
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|key, _| !key.eq_ignore_ascii_case(name.as_bytes()));
+    }
+
+    // Adds name to the Vary header unless it's listed already
+    pub fn add_vary(&mut self, name: &str) {
+        let vary = match self.get_header("Vary") {
+            Some(vary) => {
+                let vary = String::from_utf8_lossy(vary).into_owned();
+                let listed = vary
+                    .split(',')
+                    .any(|listed| listed.trim() == "*" || listed.trim().eq_ignore_ascii_case(name));
+                if listed {
+                    return;
+                }
+                format!("{}, {}", vary, name)
+            }
+            None => name.to_string(),
         };
+        self.set_header("Vary", &vary);
+    }
+
+    // Length of the body, from Content-Length when the handler set it
+    pub fn body_length(&self) -> Option<u64> {
+        self.get_header("Content-Length")
+            .and_then(|val| std::str::from_utf8(val).ok())
+            .and_then(|val| val.trim().parse().ok())
+            .or_else(|| self.body.length())
+    }
+
+    pub fn take_body(&mut self) -> Body {
+        mem::take(&mut self.body)
+    }
+
+    pub fn set_body(&mut self, body: Body) {
+        self.body = body;
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
+    // For HEAD: the headers, Content-Length included, stay those of the full
+    // response, but the body is never sent
+    pub fn strip_body(&mut self) {
+        self.head_only = true;
+    }
+
+    // Header names are case-insensitive
+    pub fn get_header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, val)| &val[..])
+    }
+
+    // Decide the framing of the body for a client speaking protocol.
+    // A Content-Length set by the handler is respected, unknown lengths are
+    // sent chunked to HTTP/1.1 clients.
+    pub fn framing(&self, protocol: &HttpProtocol) -> Framing {
+        if !self.status_code.allows_content() {
+            return Framing::Length(0);
+        }
+        let content_length = self
+            .get_header("Content-Length")
+            .and_then(|val| std::str::from_utf8(val).ok())
+            .and_then(|val| val.trim().parse().ok());
+        if let Some(length) = content_length {
+            return Framing::Length(length);
+        }
+
+        match (self.body.length(), protocol) {
+            (Some(length), _) => Framing::Length(length),
+            (None, HttpProtocol::HTTP1_0) => Framing::UntilClose,
+            (None, _) => Framing::Chunked,
+        }
+    }
+
+    pub fn serialize_head(&self, framing: Framing) -> Vec<u8> {
         let mut raw_response: Vec<u8> = Vec::new();
 
         // make response
@@ -242,25 +413,49 @@ impl Response {
         raw_response.extend(b"\r\n");
 
         // headers
+        // Transfer-Encoding is decided by the framing, not by the handler
         for (key, val) in self.headers.iter() {
+            if key.eq_ignore_ascii_case(b"Transfer-Encoding") {
+                continue;
+            }
             raw_response.extend(key);
             raw_response.extend(b": ");
             raw_response.extend(val);
             raw_response.extend(b"\r\n");
         }
-        if !self.headers.contains_key(&b"Content-Length".to_vec()) {
-            let buf = format!("Content-Length: {}\r\n", self.body.len());
-            raw_response.extend(buf.as_bytes());
-        }
 
         // some auto headers
+        match framing {
+            Framing::Length(length)
+                if self.get_header("Content-Length").is_none() && self.status_code.allows_content() =>
+            {
+                let buf = format!("Content-Length: {}\r\n", length);
+                raw_response.extend(buf.as_bytes());
+            }
+            Framing::Chunked => raw_response.extend(b"Transfer-Encoding: chunked\r\n"),
+            _ => {}
+        }
 
-        // body
         raw_response.extend(b"\r\n");
-        raw_response.extend(&self.body);
 
         raw_response
     }
+
+    pub fn into_encoder(self, framing: Framing) -> ResponseEncoder {
+        let head = self.serialize_head(framing);
+        let remaining = match framing {
+            Framing::Length(length) => Some(length),
+            _ => None,
+        };
+
+        ResponseEncoder {
+            head: Some(head),
+            body: self.body,
+            framing,
+            remaining,
+            finished: self.head_only || !self.status_code.allows_content(),
+        }
+    }
 }
 
 impl Default for Response {
@@ -270,6 +465,63 @@ impl Default for Response {
             status_code: StatusCode::NotFound,
             headers: Default::default(),
             body: Default::default(),
+            head_only: false,
+        }
+    }
+}
+
+// Produces the raw bytes of a response piece by piece, so a streamed body
+// never has to be held in memory as a whole
+pub struct ResponseEncoder {
+    head: Option<Vec<u8>>,
+    body: Body,
+    framing: Framing,
+    // Bytes still owed for Framing::Length
+    remaining: Option<u64>,
+    finished: bool,
+}
+
+impl ResponseEncoder {
+    // Next piece of the raw response, None once everything has been produced
+    pub fn next_bytes(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Some(head) = self.head.take() {
+            return Ok(Some(head));
+        }
+        if self.finished {
+            return Ok(None);
+        }
+
+        let data = match self.remaining {
+            Some(0) => None,
+            _ => self.body.next_data()?,
+        };
+
+        match (self.framing, data) {
+            (Framing::Chunked, Some(data)) => {
+                let mut piece = format!("{:x}\r\n", data.len()).into_bytes();
+                piece.extend(data);
+                piece.extend(b"\r\n");
+                Ok(Some(piece))
+            }
+            (Framing::Chunked, None) => {
+                self.finished = true;
+                Ok(Some(b"0\r\n\r\n".to_vec()))
+            }
+            (Framing::Length(_), Some(mut data)) => {
+                let remaining = self.remaining.unwrap_or_default();
+                data.truncate(remaining.min(data.len() as u64) as usize);
+                self.remaining = Some(remaining - data.len() as u64);
+                Ok(Some(data))
+            }
+            (Framing::Length(_), None) if self.remaining != Some(0) => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Body is shorter than its Content-Length",
+            )),
+            (Framing::UntilClose, Some(data)) => Ok(Some(data)),
+            (_, None) => {
+                self.finished = true;
+                Ok(None)
+            }
         }
     }
 }
@@ -278,7 +530,7 @@ pub struct ResponseBuilder {
     protocol: Option<HttpProtocol>,
     status_code: Option<StatusCode>,
     headers: Option<HashMap<Vec<u8>, Vec<u8>>>,
-    body: Option<Vec<u8>>,
+    body: Option<Body>,
 }
 
 pub enum ResponseBuilderError {
@@ -309,20 +561,46 @@ impl ResponseBuilder {
         self
     }
     pub fn body(mut self, body: Vec<u8>) -> Self {
-        self.body = Some(body);
+        self.body = Some(Body::Bytes(body));
+        self
+    }
+    // Stream the body from reader, its length is unknown
+    pub fn reader(mut self, reader: impl Read + Send + 'static) -> Self {
+        self.body = Some(Body::Reader {
+            reader: Box::new(reader),
+            length: None,
+        });
+        self
+    }
+    // Stream exactly length bytes of the body from reader
+    pub fn sized_reader(mut self, reader: impl Read + Send + 'static, length: u64) -> Self {
+        self.body = Some(Body::Reader {
+            reader: Box::new(reader),
+            length: Some(length),
+        });
+        self
+    }
+    // Stream the body as a sequence of chunks, its length is unknown
+    pub fn chunks<I>(mut self, chunks: I) -> Self
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        self.body = Some(Body::Chunks(Box::new(chunks.into_iter())));
         self
     }
     pub fn build(self) -> Result<Response, ResponseBuilderError> {
         let protocol = self.protocol.unwrap_or(HttpProtocol::HTTP1_1);
         let status_code = self.status_code.ok_or(ResponseBuilderError::NoStatusCode)?;
-        let headers = self.headers.unwrap_or(HashMap::new());
-        let body = self.body.unwrap_or(Vec::new());
+        let headers = self.headers.unwrap_or_default();
+        let body = self.body.unwrap_or_default();
 
         Ok(Response {
             protocol,
             status_code,
             headers,
             body,
+            head_only: false,
         })
     }
 }
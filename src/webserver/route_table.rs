@@ -0,0 +1,108 @@
+use std::fmt;
+
+use super::json;
+
+// The registered endpoints of an ApiEndPointManager, in registration order
+pub struct RouteTable {
+    // Global middleware, outermost first
+    pub middleware: Vec<&'static str>,
+    pub routes: Vec<RouteInfo>,
+}
+
+pub struct RouteInfo {
+    pub methods: Vec<&'static str>,
+    pub pattern: String,
+    pub handler: &'static str,
+    // Middleware of the route and its groups, outermost first
+    pub middleware: Vec<&'static str>,
+}
+
+impl RouteTable {
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"middleware\":");
+        json::push_list(&mut json, &self.middleware);
+        json.push_str(",\"routes\":[");
+        for (i, route) in self.routes.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str("{\"methods\":");
+            json::push_list(&mut json, &route.methods);
+            json.push_str(",\"pattern\":");
+            json::push_string(&mut json, &route.pattern);
+            json.push_str(",\"handler\":");
+            json::push_string(&mut json, route.handler);
+            json.push_str(",\"middleware\":");
+            json::push_list(&mut json, &route.middleware);
+            json.push('}');
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+// One row per route, columns aligned. Type names are shortened, the JSON
+// keeps them whole.
+impl fmt::Display for RouteTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = ["METHODS", "PATTERN", "HANDLER", "MIDDLEWARE"];
+        let rows: Vec<[String; 4]> = self
+            .routes
+            .iter()
+            .map(|route| {
+                [
+                    route.methods.join(","),
+                    route.pattern.clone(),
+                    short_name(route.handler).to_string(),
+                    short_names(&route.middleware),
+                ]
+            })
+            .collect();
+
+        let mut widths = header.map(str::len);
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        let header = header.map(String::from);
+        for (i, row) in std::iter::once(&header).chain(&rows).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let line = format!(
+                "{:w0$}  {:w1$}  {:w2$}  {}",
+                row[0],
+                row[1],
+                row[2],
+                row[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2],
+            );
+            write!(f, "{}", line.trim_end())?;
+        }
+        if !self.middleware.is_empty() {
+            write!(f, "\nGlobal middleware: {}", short_names(&self.middleware))?;
+        }
+        Ok(())
+    }
+}
+
+// The item name without its module path, closures keep the function they are
+// defined in. Generic names are left alone.
+fn short_name(name: &str) -> &str {
+    if name.contains('<') {
+        return name;
+    }
+    let mut start = name.rfind("::").map_or(0, |i| i + 2);
+    if name[start..].starts_with('{') {
+        start = name[..start - 2].rfind("::").map_or(0, |i| i + 2);
+    }
+    &name[start..]
+}
+
+fn short_names(names: &[&str]) -> String {
+    names.iter().map(|name| short_name(name)).collect::<Vec<_>>().join(", ")
+}
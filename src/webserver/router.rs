@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+use super::path_pattern::{PathPattern, Segment};
+
+// Prefix tree of route patterns, one level per path segment. A lookup only
+// walks the segments of the path, however many routes are registered.
+// Static segments have priority over a parameter, which has priority over a
+// wildcard.
+pub struct Router<T> {
+    root: Node<T>,
+}
+
+// Routes sharing one pattern shape that match a path
+pub struct Match<'a, 'p, T> {
+    pub routes: &'a [T],
+    // Values of the parameters and the wildcard, in pattern order
+    pub captures: Vec<&'p str>,
+}
+
+struct Node<T> {
+    statics: HashMap<String, Node<T>>,
+    param: Option<Box<Node<T>>>,
+    // Routes whose wildcard starts at this segment
+    wildcard: Vec<T>,
+    // Routes ending after this node's segment
+    values: Vec<T>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Self {
+            statics: HashMap::new(),
+            param: None,
+            wildcard: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    // Adds every route matching rest, the path after the segment leading to
+    // this node, in priority order
+    fn collect<'a, 'p>(
+        &'a self,
+        rest: &'p str,
+        captures: &mut Vec<&'p str>,
+        found: &mut Vec<Match<'a, 'p, T>>,
+    ) {
+        let (piece, remaining) = match rest.split_once('/') {
+            Some((piece, remaining)) => (piece, Some(remaining)),
+            None => (rest, None),
+        };
+
+        if let Some(child) = self.statics.get(piece) {
+            child.descend(remaining, captures, found);
+        }
+
+        if let Some(child) = self.param.as_deref().filter(|_| !piece.is_empty()) {
+            captures.push(piece);
+            child.descend(remaining, captures, found);
+            captures.pop();
+        }
+
+        if !self.wildcard.is_empty() {
+            captures.push(rest);
+            found.push(Match {
+                routes: &self.wildcard,
+                captures: captures.clone(),
+            });
+            captures.pop();
+        }
+    }
+
+    fn descend<'a, 'p>(
+        &'a self,
+        remaining: Option<&'p str>,
+        captures: &mut Vec<&'p str>,
+        found: &mut Vec<Match<'a, 'p, T>>,
+    ) {
+        match remaining {
+            Some(rest) => self.collect(rest, captures, found),
+            None if !self.values.is_empty() => found.push(Match {
+                routes: &self.values,
+                captures: captures.clone(),
+            }),
+            None => {}
+        }
+    }
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Self {
+        Self { root: Node::new() }
+    }
+
+    // Routes registered with the same shape as pattern end up in the same
+    // list, which is returned for the caller to add to
+    pub fn insert(&mut self, pattern: &PathPattern) -> &mut Vec<T> {
+        let mut node = &mut self.root;
+        for segment in pattern.segments() {
+            node = match segment {
+                Segment::Static(text) => node.statics.entry(text.clone()).or_insert_with(Node::new),
+                Segment::Param(_) => node.param.get_or_insert_with(|| Box::new(Node::new())),
+                Segment::Wildcard(_) => return &mut node.wildcard,
+            };
+        }
+        &mut node.values
+    }
+
+    // Every pattern matching path, best match first
+    pub fn lookup<'a, 'p>(&'a self, path: &'p str) -> Vec<Match<'a, 'p, T>> {
+        let mut found = Vec::new();
+        if let Some(rest) = path.strip_prefix('/') {
+            self.root.collect(rest, &mut Vec::new(), &mut found);
+        }
+        found
+    }
+}
@@ -1,100 +1,302 @@
 use std::collections::HashMap;
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread::{JoinHandle, self};
+use std::time::{Duration, Instant};
 use std::{
     fmt::Debug,
-    io::{BufRead, BufReader, Read, Write},
-    net::{TcpListener, TcpStream},
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    os::fd::AsRawFd,
 };
 
-use log::{debug, error, info};
+use log::{debug, error, info, trace, warn};
 
-use crate::webserver::request::Request;
+use crate::webserver::{
+    request::Request,
+    response::{Response, ResponseEncoder, StatusCode},
+};
+
+use super::{
+    api_endpoint_manager::ApiEndPointManager,
+    event_loop::EventLoop,
+    chunked::ChunkedDecoder,
+    config::{KeepAliveConfig, LimitsConfig, ServerConfig, ThreadPoolConfig},
+    http_utils::HttpProtocol,
+    listener::{self, Listener},
+    request::{body_framing, parse_request_before_body, wants_keep_alive, BodyFraming},
+    shutdown::{self, Readiness},
+    stream::Stream,
+};
 
-use super::{api_endpoint_manager::ApiEndPointManager, request::parse_request_before_body};
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 struct Worker {
     join_handle: Option<JoinHandle<()>>,
 }
 
+impl Worker {
+    fn is_finished(&self) -> bool {
+        self.join_handle
+            .as_ref()
+            .is_none_or(|handle| handle.is_finished())
+    }
+}
+
 struct ThreadPool {
     workers: Vec<Worker>,
-    receiver: Arc<Mutex<Receiver<TcpStream>>>,
-    sender: Sender<TcpStream>,
+    receiver: Arc<Mutex<Receiver<Stream>>>,
+    // Dropped on shutdown so idle workers stop waiting for connections
+    sender: Option<SyncSender<Stream>>,
 }
 
 impl ThreadPool {
-    fn new() -> Self {
-        let (tx, rx) = mpsc::channel::<TcpStream>();
+    fn new(queue_capacity: usize) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<Stream>(queue_capacity);
         let mutex = Mutex::new(rx);
         Self {
             workers: Vec::new(),
             receiver: Arc::new(mutex),
-            sender: tx,
+            sender: Some(tx),
         }
     }
 
-    fn spawn_threads(&mut self, manager: Arc<ApiEndPointManager>, num_threads: i32) {
-        for _ in 0..num_threads {
+    fn spawn_threads(
+        &mut self,
+        manager: Arc<ApiEndPointManager>,
+        keep_alive: KeepAliveConfig,
+        limits: LimitsConfig,
+        config: &ThreadPoolConfig,
+    ) -> io::Result<()> {
+        for i in 0..config.workers {
             let manager = Arc::clone(&manager);
             let rx = self.receiver.clone();
-            let join_handle = std::thread::spawn(|| {
+            let mut builder = thread::Builder::new().name(format!("worker-{}", i));
+            if let Some(stack_size) = config.stack_size {
+                builder = builder.stack_size(stack_size);
+            }
+            let join_handle = builder.spawn(move || {
                 info!("Created: {:?}", thread::current());
-                thread_main(manager, rx);
-            });
+                thread_main(manager, rx, keep_alive, limits);
+            })?;
             let join_handle = Some(join_handle);
 
             self.workers.push(Worker { join_handle });
         }
+        Ok(())
     }
-}
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        
+    // Stops handing out connections and gives the workers until timeout to
+    // finish the ones they have. Workers still busy after that are left
+    // behind and end with the process.
+    fn shutdown(&mut self, timeout: Duration) {
+        if self.sender.take().is_none() {
+            return;
+        }
+
+        let drained = wait_until(timeout, || self.workers.iter().all(Worker::is_finished));
+        if !drained {
+            let busy = self.workers.iter().filter(|w| !w.is_finished()).count();
+            warn!("Shutdown deadline passed with {} busy workers", busy);
+        }
+
         for worker in self.workers.iter_mut() {
+            if !worker.is_finished() {
+                continue;
+            }
             info!("Shutting down worker");
             if let Some(handle) = worker.join_handle.take() {
-                handle.join().unwrap();
+                if handle.join().is_err() {
+                    error!("Worker panicked");
+                }
             }
         }
     }
 }
 
-#[allow(dead_code)]
-pub fn run_ipv4_server_event_based(ip: &str, port: u16) {}
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.shutdown(Duration::ZERO);
+    }
+}
 
-#[allow(dead_code)]
-pub fn run_ipv4_server_multithreaded(ip: &str, port: u16) {
-    info!("Starting Server (multi) on {}:{}...", ip, port);
+// Counts a connection as active for as long as it is alive
+struct ActiveConnection(Arc<AtomicUsize>);
 
-    let ip_port_string = format!("{}:{}", ip, port);
-    let listener = TcpListener::bind(ip_port_string).unwrap();
+impl ActiveConnection {
+    fn new(counter: &Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Self(Arc::clone(counter))
+    }
+}
 
-    let manager = ApiEndPointManager::get();
-    let manager = Arc::new(manager);
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
-    let mut thread_pool = ThreadPool::new();
-    thread_pool.spawn_threads(manager, 1024);
+// Waits until done returns true or timeout passes, returns whether it did
+fn wait_until(timeout: Duration, done: impl Fn() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !done() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(DRAIN_POLL_INTERVAL);
+    }
+    true
+}
 
-    info!("Started...");
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let result = thread_pool.sender.send(stream);
-                if let Err(e) = result {
-                    error!("Error while distributing TcpStream: {e}")
+// Accepts connections until a shutdown is requested
+fn accept_until_shutdown(listeners: &[Listener], mut on_accept: impl FnMut(Stream)) {
+    for listener in listeners {
+        if let Err(e) = listener.set_nonblocking(true) {
+            error!("Error in setting listener non-blocking: {}", e);
+            return;
+        }
+    }
+    let fds: Vec<_> = listeners.iter().map(|listener| listener.as_raw_fd()).collect();
+
+    loop {
+        match shutdown::wait_any_readable(&fds, None) {
+            // Listeners with nothing pending just return WouldBlock
+            Ok(Readiness::Readable) => {
+                for listener in listeners {
+                    match listener.accept() {
+                        Ok(stream) => on_accept(stream),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                        Err(e) => error!("Connection Failed, {}", e),
+                    }
                 }
             }
+            Ok(Readiness::TimedOut) => {}
+            Ok(Readiness::Shutdown) => {
+                info!("Shutdown requested, no longer accepting connections");
+                return;
+            }
             Err(e) => {
-                error!("Connection Failed, {}", e);
+                error!("Error in waiting for connections: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+fn install_shutdown_handler() {
+    if let Err(e) = shutdown::install() {
+        error!("Error in installing signal handlers: {}", e);
+    }
+}
+
+// Binds the listeners, errors are logged
+fn start(config: &ServerConfig) -> Option<Vec<Listener>> {
+    install_shutdown_handler();
+
+    match listener::bind_all(&config.listen) {
+        Ok(listeners) => Some(listeners),
+        Err(e) => {
+            error!("Error in binding listeners: {}", e);
+            None
+        }
+    }
+}
+
+pub fn run_server_event_based(config: &ServerConfig, manager: ApiEndPointManager) {
+    info!("Starting Server (event)...");
+    let listeners = match start(config) {
+        Some(listeners) => listeners,
+        None => return,
+    };
+    let manager = Arc::new(manager);
+
+    let mut event_loop = EventLoop::new(
+        listeners,
+        manager,
+        config.keep_alive,
+        config.limits,
+        config.shutdown_timeout,
+    )
+    .unwrap();
+
+    info!("Started...");
+    if let Err(e) = event_loop.run() {
+        error!("Event loop stopped: {}", e);
+    }
+    info!("Server stopped");
+}
+
+pub fn run_server_multithreaded(config: &ServerConfig, manager: ApiEndPointManager) {
+    info!("Starting Server (multi)...");
+    let listeners = match start(config) {
+        Some(listeners) => listeners,
+        None => return,
+    };
+    let manager = Arc::new(manager);
+
+    let pool_config = config.thread_pool;
+    let mut thread_pool = ThreadPool::new(pool_config.queue_capacity);
+    thread_pool
+        .spawn_threads(manager, config.keep_alive, config.limits, &pool_config)
+        .unwrap();
+
+    info!("Started...");
+    accept_until_shutdown(&listeners, |stream| {
+        let sender = match &thread_pool.sender {
+            Some(sender) => sender,
+            None => return,
+        };
+        match sender.try_send(stream) {
+            Ok(()) => {}
+            Err(TrySendError::Full(stream)) => {
+                warn!("Worker queue is full, rejecting connection");
+                reject_busy(stream, pool_config.retry_after);
             }
+            Err(e) => error!("Error while distributing Stream: {e}"),
         }
+    });
+
+    drop(listeners);
+    thread_pool.shutdown(config.shutdown_timeout);
+    info!("Server stopped");
+}
+
+// Answers 503 without reading the request, so a burst can't grow the queue.
+// The acceptor must never wait on the client: the response goes out in one
+// non-blocking write, which the empty send buffer of a new socket takes
+// whole. A TLS client would need a handshake first, so it's just closed.
+fn reject_busy(mut stream: Stream, retry_after: Duration) {
+    if let Err(e) = stream.tcp().set_nonblocking(true) {
+        error!("Error in setting stream non-blocking: {}", e);
+        return;
+    }
+    if stream.is_tls() {
+        return;
+    }
+
+    let response = Response::builder()
+        .status_code(StatusCode::ServiceUnavailable)
+        .header("Retry-After", &retry_after.as_secs().to_string())
+        .build()
+        .unwrap_or_default();
+    let (mut encoder, _) = encode_response(response, &HttpProtocol::HTTP1_1, false);
+    let mut bytes = Vec::new();
+    while let Ok(Some(piece)) = encoder.next_bytes() {
+        bytes.extend_from_slice(&piece);
+    }
+    match stream.write(&bytes) {
+        Ok(written) if written == bytes.len() => {}
+        Ok(_) => debug!("Busy response only partly written"),
+        Err(e) => debug!("Error in writing busy response: {}", e),
     }
 }
 
-pub fn thread_main(manager: Arc<ApiEndPointManager>, rx: Arc<Mutex<Receiver<TcpStream>>>) {
+pub fn thread_main(
+    manager: Arc<ApiEndPointManager>,
+    rx: Arc<Mutex<Receiver<Stream>>>,
+    keep_alive: KeepAliveConfig,
+    limits: LimitsConfig,
+) {
     loop {
         let acquire = rx.lock();
         info!("{:?} alive", thread::current());
@@ -106,109 +308,270 @@ pub fn thread_main(manager: Arc<ApiEndPointManager>, rx: Arc<Mutex<Receiver<TcpS
             }
         };
         
+        // Fails once the pool is shutting down and the queue is empty
         let stream = match receiver.recv() {
             Ok(stream) => stream,
-            Err(e) => {
-                error!("Error while receiving TcpStream, {e}");
-                continue;
+            Err(_) => {
+                info!("{:?} stopping", thread::current());
+                return;
             }
         };
 
         drop(receiver);
 
+        handle_stream(manager.clone(), stream, keep_alive, limits);
+    }
+}
+
+pub fn run_server(config: &ServerConfig, manager: ApiEndPointManager) {
+    info!("Starting Server...");
+    let listeners = match start(config) {
+        Some(listeners) => listeners,
+        None => return,
+    };
+    let manager = Arc::new(manager);
+
+    let (keep_alive, limits) = (config.keep_alive, config.limits);
+    let active = Arc::new(AtomicUsize::new(0));
 
-        handle_stream(manager.clone(), stream);    
+    info!("Started...");
+    accept_until_shutdown(&listeners, |stream| {
+        let manager = Arc::clone(&manager);
+        let connection = ActiveConnection::new(&active);
+        std::thread::spawn(move || {
+            handle_stream(manager, stream, keep_alive, limits);
+            drop(connection);
+        });
+    });
+
+    drop(listeners);
+    if !wait_until(config.shutdown_timeout, || active.load(Ordering::SeqCst) == 0) {
+        let remaining = active.load(Ordering::SeqCst);
+        warn!("Shutdown deadline passed with {} open connections", remaining);
     }
+    info!("Server stopped");
 }
 
-#[allow(dead_code)]
-pub fn run_ipv4_server(ip: &str, port: u16) {
-    info!("Starting Server on {}:{}...", ip, port);
+pub fn handle_stream(
+    endpoint_manager: Arc<ApiEndPointManager>,
+    mut stream: Stream,
+    keep_alive: KeepAliveConfig,
+    limits: LimitsConfig,
+) {
+    // A zero timeout means none, the socket refuses a zero read timeout
+    let timeout = Some(keep_alive.timeout).filter(|timeout| !timeout.is_zero());
+    if let Err(e) = stream.tcp().set_read_timeout(timeout) {
+        // Without the timeout a stalled client could hold the thread forever
+        error!("Error in setting read timeout: {}", e);
+        let response = error_response(StatusCode::InternalServerError);
+        write_response(&mut stream, response, &HttpProtocol::HTTP1_1, false);
+        return;
+    }
 
-    let ip_port_string = format!("{}:{}", ip, port);
-    let listener = TcpListener::bind(ip_port_string).unwrap();
+    // The reader is kept across requests so pipelined bytes aren't lost
+    let mut reader = BufReader::new(stream);
+    let mut served = 0;
 
-    let manager = ApiEndPointManager::get();
-    let manager = Arc::new(manager);
+    loop {
+        // Wait for the next request unless it is already buffered
+        if reader.buffer().is_empty() && !reader.get_mut().has_buffered_data() {
+            let fd = reader.get_ref().as_raw_fd();
+            match shutdown::wait_readable(fd, timeout) {
+                Ok(Readiness::Readable) => {}
+                Ok(Readiness::TimedOut) => {
+                    trace!("Closing idle connection");
+                    return;
+                }
+                Ok(Readiness::Shutdown) => {
+                    trace!("Closing idle connection for shutdown");
+                    return;
+                }
+                Err(e) => {
+                    error!("Error in waiting for request: {}", e);
+                    return;
+                }
+            }
+        }
 
-    info!("Started...");
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let manager = Arc::clone(&manager);
-                std::thread::spawn(|| {
-                    handle_stream(manager, stream);
-                });
+        let mut request_buf = Vec::new();
+
+        // Parse request line and headers
+        match read_until_double_crlf(&mut reader, &mut request_buf, limits.max_head_size) {
+            Ok(0) => {
+                trace!("Connection closed by client");
+                return;
+            }
+            Ok(_) => {}
+            Err(e) if request_buf.is_empty() => {
+                trace!("Closing idle connection: {}", e);
+                return;
             }
             Err(e) => {
-                error!("Connection Failed, {}", e);
+                error!("Error in reading before body: {}", e);
+                return;
             }
         }
-    }
-}
 
-pub fn handle_stream(endpoint_manager: Arc<ApiEndPointManager>, mut stream: TcpStream) {
-    let mut request_buf = Vec::new();
-    let mut reader = BufReader::new(&stream);
+        if request_buf.len() > limits.max_head_size {
+            error!("Rejecting request: head larger than {} bytes", limits.max_head_size);
+            let response = error_response(StatusCode::RequestHeaderFieldsTooLarge);
+            write_response(reader.get_mut(), response, &HttpProtocol::HTTP1_1, false);
+            return;
+        }
 
-    // Parse request line and headers
-    let result = read_until_double_crlf(&mut reader, &mut request_buf);
-    if let Err(e) = result {
-        error!("Error in reading before body: {}", e);
-    }
+        let (request_line, headers) = match parse_request_before_body(&request_buf) {
+            Ok(request) => request,
+            Err(e) => {
+                error!("Parsing Error! {}", e);
+                let response = error_response(StatusCode::BadRequest);
+                write_response(reader.get_mut(), response, &HttpProtocol::HTTP1_1, false);
+                return;
+            }
+        };
 
-    let (request_line, headers) = match parse_request_before_body(&request_buf) {
-        Ok(request) => request,
-        Err(e) => {
-            error!("Parsing Error! {}", e);
+        // Get body
+        let framing = match body_framing(&headers) {
+            Ok(framing) => framing,
+            Err(e) => {
+                error!("Rejecting request: {}", e.message());
+                write_response(reader.get_mut(), error_response(e.status_code()), &HttpProtocol::HTTP1_1, false);
+                return;
+            }
+        };
+
+        if matches!(framing, BodyFraming::Length(size) if size > limits.max_body_size) {
+            error!("Rejecting request: body larger than {} bytes", limits.max_body_size);
+            let response = error_response(StatusCode::PayloadTooLarge);
+            write_response(reader.get_mut(), response, &HttpProtocol::HTTP1_1, false);
             return;
         }
-    };
 
-    // Get body
-    let mut body_buf = Vec::new();
-    let result = read_body(&mut reader, &mut body_buf, &headers).unwrap_or_else(|e| {
-        info!("Determined body doesn't exist: {}", e);
-        return 0;
-    });
+        let mut body_buf = Vec::new();
+        let mut decoder = ChunkedDecoder::new(limits.max_body_size, limits.max_head_size);
+        if let Err((status_code, e)) = read_body(&mut reader, &mut body_buf, &framing, &mut decoder) {
+            error!("Error in reading body: {}", e);
+            let response = error_response(status_code);
+            write_response(reader.get_mut(), response, &HttpProtocol::HTTP1_1, false);
+            return;
+        }
+        let trailers = match decoder.trailers() {
+            Ok(trailers) => trailers,
+            Err(e) => {
+                error!("Parsing Error! {}", e);
+                let response = error_response(StatusCode::BadRequest);
+                write_response(reader.get_mut(), response, &HttpProtocol::HTTP1_1, false);
+                return;
+            }
+        };
 
-    let body = &body_buf[..result];
-
-    debug!(
-        "\n{:?} {:} {:?}\n{:?}\n{:}",
-        request_line.method,
-        request_line.path,
-        request_line.protocol,
-        HeaderDebugWrapper {
-            headers: headers.clone()
-        },
-        String::from_utf8_lossy(body),
-    );
-
-    // Make request struct
-    let request = Request {
-        request_line,
-        headers,
-        body,
-    };
+        let body = &body_buf[..];
+
+        debug!(
+            "\n{:?} {:} {:?}\n{:?}\n{:}",
+            request_line.method,
+            request_line.path,
+            request_line.protocol,
+            HeaderDebugWrapper {
+                headers: headers.clone()
+            },
+            String::from_utf8_lossy(body),
+        );
+
+        // Make request struct
+        let mut request = Request::new(request_line, headers, body, trailers);
+
+        let response = endpoint_manager.handle_request(&mut request);
+
+        // Checked after the handler, a shutdown may have started meanwhile
+        served += 1;
+        let keep_connection = wants_keep_alive(&request.request_line.protocol, &request.headers)
+            && served < keep_alive.max_requests
+            && !shutdown::requested();
+
+        let protocol = &request.request_line.protocol;
+        if !write_response(reader.get_mut(), response, protocol, keep_connection) {
+            return;
+        }
+    }
+}
+
+// Returns whether the connection can serve another request
+fn write_response(
+    stream: &mut Stream,
+    response: Response,
+    protocol: &HttpProtocol,
+    keep_alive: bool,
+) -> bool {
+    let (mut encoder, keep_alive) = encode_response(response, protocol, keep_alive);
+    loop {
+        match encoder.next_bytes() {
+            Ok(Some(bytes)) => {
+                debug!("raw_response\n{}", String::from_utf8_lossy(&bytes));
+                if let Err(e) = stream.write_all(&bytes) {
+                    error!("Error in writing response: {}", e);
+                    return false;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("Error in producing response body: {}", e);
+                return false;
+            }
+        }
+    }
+
+    // TLS may still hold the end of the response
+    if let Err(e) = stream.flush() {
+        error!("Error in writing response: {}", e);
+        return false;
+    }
+    keep_alive
+}
+
+// Frames the response for a client speaking protocol and sets its
+// Connection header. Also returns whether the connection can be kept open,
+// which isn't the case when the body is delimited by closing it.
+pub fn encode_response(
+    mut response: Response,
+    protocol: &HttpProtocol,
+    keep_alive: bool,
+) -> (ResponseEncoder, bool) {
+    let framing = response.framing(protocol);
+    let keep_alive = keep_alive && !framing.closes_connection();
+    set_connection_header(&mut response, keep_alive);
+    (response.into_encoder(framing), keep_alive)
+}
+
+fn set_connection_header(response: &mut Response, keep_alive: bool) {
+    let value = if keep_alive { "keep-alive" } else { "close" };
+    response.set_header("Connection", value);
+}
 
-    let response = endpoint_manager.handle_request(&request);
-    let raw_response = response.serialize();
-    debug!("raw_response\n{}", String::from_utf8_lossy(&raw_response));
-    let _ = stream.write_all(&raw_response).unwrap();
+pub fn error_response(status_code: StatusCode) -> Response {
+    Response::builder()
+        .status_code(status_code)
+        .build()
+        .unwrap_or_default()
 }
 
+// Stops once buf holds more than max_size bytes, the head is too large then
 fn read_until_double_crlf(
-    reader: &mut BufReader<&TcpStream>,
+    reader: &mut BufReader<Stream>,
     buf: &mut Vec<u8>,
+    max_size: usize,
 ) -> Result<usize, &'static str> {
-    let total_bytes_read = 0;
+    let mut total_bytes_read = 0;
     loop {
-        let read_result = reader.read_until(b'\n', buf);
+        let limit = (max_size + 1).saturating_sub(buf.len()) as u64;
+        if limit == 0 {
+            break;
+        }
+        let read_result = reader.by_ref().take(limit).read_until(b'\n', buf);
         let bytes_read = match read_result {
             Ok(count) => count,
             Err(_) => return Err("Error in reading until double crlf"),
         };
+        total_bytes_read += bytes_read;
 
         if bytes_read == 0 || buf.ends_with(b"\r\n\r\n") {
             break;
@@ -218,51 +581,55 @@ fn read_until_double_crlf(
 }
 
 fn read_body(
-    reader: &mut BufReader<&TcpStream>,
-    request_buf: &mut Vec<u8>,
-    headers: &std::collections::HashMap<&[u8], &[u8]>,
-) -> Result<usize, &'static str> {
-    // Get size from Content-Length
-    let size = headers
-        .get(&b"Content-Length"[..])
-        .ok_or("No Content Length")?;
-    let size = byte_slice_to_i32(size)?;
-
-    // Read size bytes from reader
-    let mut chunk = reader.take(size as u64);
-    let bytes_read = chunk
-        .read_to_end(request_buf)
-        .map_err(|_| "Error in reading body")?;
-
-    Ok(bytes_read)
-}
-
-fn byte_slice_to_i32(bytes_slice: &[u8]) -> Result<i32, &'static str> {
-    let mut total: i32 = 0;
-    for &byte in bytes_slice {
-        if b'0' <= byte && byte <= b'9' {
-            let cur = (byte - b'0') as i32;
-            total *= 10;
-            total += cur;
-        } else {
-            return Err("Error in parsing byte slice to i32");
+    reader: &mut BufReader<Stream>,
+    body_buf: &mut Vec<u8>,
+    framing: &BodyFraming,
+    decoder: &mut ChunkedDecoder,
+) -> Result<(), (StatusCode, &'static str)> {
+    let bad_request = |message| (StatusCode::BadRequest, message);
+    match framing {
+        BodyFraming::None => Ok(()),
+        BodyFraming::Length(size) => {
+            // Read size bytes from reader
+            let mut chunk = reader.take(*size as u64);
+            let bytes_read = chunk
+                .read_to_end(body_buf)
+                .map_err(|_| bad_request("Error in reading body"))?;
+            if bytes_read < *size {
+                return Err(bad_request("Connection closed before end of body"));
+            }
+            Ok(())
+        }
+        BodyFraming::Chunked => {
+            while !decoder.is_done() {
+                let available = reader
+                    .fill_buf()
+                    .map_err(|_| bad_request("Error in reading chunked body"))?;
+                if available.is_empty() {
+                    return Err(bad_request("Connection closed before end of chunked body"));
+                }
+                let consumed = decoder
+                    .decode(available, body_buf)
+                    .map_err(|e| (e.status_code(), e.message()))?;
+                reader.consume(consumed);
+            }
+            Ok(())
         }
     }
-    Ok(total)
 }
 
-struct HeaderDebugWrapper<'a> {
-    headers: HashMap<&'a [u8], &'a [u8]>,
+pub struct HeaderDebugWrapper<'a> {
+    pub headers: HashMap<&'a [u8], &'a [u8]>,
 }
 
 impl Debug for HeaderDebugWrapper<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for (&key, &val) in self.headers.iter() {
-            write!(
+            writeln!(
                 f,
-                "{}: {}\n",
-                String::from_utf8_lossy(&key),
-                String::from_utf8_lossy(&val)
+                "{}: {}",
+                String::from_utf8_lossy(key),
+                String::from_utf8_lossy(val)
             )?;
         }
         Ok(())
@@ -0,0 +1,107 @@
+use std::{
+    io, mem, ptr,
+    os::fd::RawFd,
+    sync::atomic::{AtomicBool, AtomicI32, Ordering},
+    time::Duration,
+};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+// Self-pipe written by the signal handler. The read end is never drained, so
+// once a shutdown is requested it stays readable for every poller.
+static WAKE_READ_FD: AtomicI32 = AtomicI32::new(-1);
+static WAKE_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+pub enum Readiness {
+    Readable,
+    TimedOut,
+    Shutdown,
+}
+
+extern "C" fn handle_signal(_signal: libc::c_int) {
+    // A second signal doesn't wait for the drain
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(1) };
+    }
+    let fd = WAKE_WRITE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe { libc::write(fd, b"x".as_ptr() as *const libc::c_void, 1) };
+    }
+}
+
+// Installs the SIGINT and SIGTERM handlers requesting a graceful shutdown
+pub fn install() -> io::Result<()> {
+    if WAKE_READ_FD.load(Ordering::SeqCst) >= 0 {
+        return Ok(());
+    }
+
+    let mut fds = [-1; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    WAKE_READ_FD.store(fds[0], Ordering::SeqCst);
+    WAKE_WRITE_FD.store(fds[1], Ordering::SeqCst);
+
+    let mut action: libc::sigaction = unsafe { mem::zeroed() };
+    action.sa_sigaction = handle_signal as *const () as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        if unsafe { libc::sigaction(signal, &action, ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+// Becomes readable once a shutdown is requested, -1 before install
+pub fn wake_fd() -> RawFd {
+    WAKE_READ_FD.load(Ordering::SeqCst)
+}
+
+// Waits until fd is readable, timeout passes or a shutdown is requested.
+// Data already waiting on fd wins over a shutdown.
+pub fn wait_readable(fd: RawFd, timeout: Option<Duration>) -> io::Result<Readiness> {
+    wait_any_readable(&[fd], timeout)
+}
+
+// Same as wait_readable, Readable once any of fds is
+pub fn wait_any_readable(fds: &[RawFd], timeout: Option<Duration>) -> io::Result<Readiness> {
+    let timeout_ms = timeout.map_or(-1, |timeout| timeout.as_millis().min(i32::MAX as u128) as i32);
+    loop {
+        let mut poll_fds: Vec<libc::pollfd> = fds
+            .iter()
+            .chain([wake_fd()].iter())
+            .map(|&fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let result =
+            unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, timeout_ms) };
+        if result < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted && !requested() {
+                continue;
+            }
+            if requested() {
+                return Ok(Readiness::Shutdown);
+            }
+            return Err(e);
+        }
+
+        let (ready, wake) = poll_fds.split_at(fds.len());
+        return Ok(if ready.iter().any(|fd| fd.revents != 0) {
+            Readiness::Readable
+        } else if wake[0].revents != 0 || requested() {
+            Readiness::Shutdown
+        } else {
+            Readiness::TimedOut
+        });
+    }
+}
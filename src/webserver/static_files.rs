@@ -0,0 +1,503 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, error, warn};
+
+use super::{
+    compression::{self, Compression},
+    conditional::{self, Precondition, Validators},
+    config::{CompressionConfig, ContentCoding, EtagSource, StaticFilesConfig},
+    directory_listing,
+    http_utils::{self, format_http_date, percent_encode_path},
+    mime::MimeTypes,
+    range::{self, ByteRange, MultipartRanges},
+    request::{get_header, Request},
+    response::{Response, ResponseBuilder, StatusCode},
+};
+
+// Serves files below root, the request path is resolved relative to it
+pub struct StaticFiles {
+    root: PathBuf,
+    mime_types: MimeTypes,
+    nosniff: bool,
+    etag_source: EtagSource,
+    content_etags: Mutex<HashMap<PathBuf, ContentEtag>>,
+    index_files: Vec<String>,
+    directory_listing: bool,
+    // Encodings looked for as siblings of a file, in order of preference
+    precompressed: Vec<ContentCoding>,
+    // Whose Vary and ETag a 304 has to match
+    compression: Compression,
+}
+
+// Content hash of a file, valid while its size and modification time stay
+// the same
+struct ContentEtag {
+    length: u64,
+    modified: Option<SystemTime>,
+    etag: String,
+}
+
+impl StaticFiles {
+    pub fn new(config: &StaticFilesConfig, compression: &CompressionConfig) -> Self {
+        let precompressed = if config.precompressed {
+            compression.encodings.clone()
+        } else {
+            Vec::new()
+        };
+        Self {
+            root: config.root.clone(),
+            mime_types: MimeTypes::new(&config.mime_types, &config.charset),
+            nosniff: config.nosniff,
+            etag_source: config.etag,
+            content_etags: Mutex::new(HashMap::new()),
+            index_files: config.index_files.clone(),
+            directory_listing: config.directory_listing,
+            precompressed,
+            compression: Compression::new(compression),
+        }
+    }
+
+    // The path is expected to be normalized already. Symlinks are resolved
+    // before the check, so a link pointing outside of root is refused too.
+    pub fn serve(&self, request: &Request) -> Response {
+        let root = match fs::canonicalize(&self.root) {
+            Ok(root) => root,
+            Err(e) => {
+                error!("Static root {}: {}", self.root.display(), e);
+                return status(StatusCode::InternalServerError);
+            }
+        };
+
+        let requested = root.join(request.request_line.path.trim_start_matches('/'));
+        debug!("path: {:?}", requested);
+        let path = match fs::canonicalize(&requested) {
+            Ok(path) => path,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return status(StatusCode::NotFound),
+            Err(e) => {
+                error!("Error in serving static file: {}", e);
+                return status(StatusCode::NotFound);
+            }
+        };
+        if !path.starts_with(&root) {
+            warn!("Refused {:?}, it resolves outside of the static root", requested);
+            return status(StatusCode::Forbidden);
+        }
+
+        if path.is_dir() {
+            return self.serve_directory(request, &root, &path);
+        }
+        self.serve_file(request, &root, &path, &requested)
+    }
+
+    // The content type comes from the requested name, which may be a symlink
+    // to a file with another extension. A precompressed sibling is served in
+    // place of the file when the client accepts its encoding, except to a
+    // request for ranges, which address the uncompressed file.
+    fn serve_file(&self, request: &Request, root: &Path, path: &Path, requested: &Path) -> Response {
+        let siblings = self.precompressed_siblings(root, path);
+        let coding = if get_header(&request.headers, "Range").is_some() {
+            None
+        } else {
+            let available: Vec<ContentCoding> = siblings.iter().map(|&(coding, _)| coding).collect();
+            compression::negotiate(request, &available)
+        };
+        let served_path = siblings
+            .iter()
+            .find(|&&(sibling, _)| Some(sibling) == coding)
+            .map_or(path, |(_, sibling)| sibling.as_path());
+
+        let mut response = self.serve_path(request, served_path, requested, coding);
+        if !siblings.is_empty() {
+            response.add_vary("Accept-Encoding");
+        }
+        response
+    }
+
+    fn serve_path(
+        &self,
+        request: &Request,
+        path: &Path,
+        requested: &Path,
+        coding: Option<ContentCoding>,
+    ) -> Response {
+        let served_file =
+            File::open(path).and_then(|file| file.metadata().map(|metadata| (file, metadata)));
+        match served_file {
+            Ok((mut served_file, metadata)) if metadata.is_file() => {
+                let content_type = self.mime_types.content_type(requested);
+                let validators = Validators {
+                    etag: self.entity_tag(path, &mut served_file, &metadata),
+                    last_modified: metadata.modified().ok(),
+                };
+                let representation = Representation {
+                    content_type: &content_type,
+                    coding,
+                };
+                self.file_response(request, served_file, &metadata, &validators, representation)
+            }
+            Ok(_) => status(StatusCode::NotFound),
+            Err(e) => {
+                error!("Error in serving static file: {}", e);
+                status(StatusCode::NotFound)
+            }
+        }
+    }
+
+    // Relative links only work below `/dir/`, so `/dir` is redirected there
+    // first. Then the first index file present is served, or else the
+    // listing if it's enabled.
+    fn serve_directory(&self, request: &Request, root: &Path, path: &Path) -> Response {
+        let request_path = &request.request_line.path;
+        if !request_path.ends_with('/') {
+            let mut location = percent_encode_path(request_path) + "/";
+            if let Some(query) = &request.request_line.query {
+                location.push('?');
+                location.push_str(query);
+            }
+            return Response::builder()
+                .status_code(StatusCode::MovedPermanently)
+                .header("Location", &location)
+                .build()
+                .unwrap_or_default();
+        }
+
+        for index_file in &self.index_files {
+            let requested = path.join(index_file);
+            let index_path = match fs::canonicalize(&requested) {
+                Ok(index_path) if index_path.starts_with(root) && index_path.is_file() => index_path,
+                _ => continue,
+            };
+            return self.serve_file(request, root, &index_path, &requested);
+        }
+
+        if !self.directory_listing {
+            return status(StatusCode::NotFound);
+        }
+        self.listing_response(request, path)
+    }
+
+    fn listing_response(&self, request: &Request, path: &Path) -> Response {
+        let mut entries = match directory_listing::read_entries(path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Error in listing {}: {}", path.display(), e);
+                return status(StatusCode::InternalServerError);
+            }
+        };
+        let sort_key = directory_listing::SortKey::parse(request.query.get("sort"));
+        let descending = request.query.get("order") == Some("desc");
+        directory_listing::sort(&mut entries, sort_key, descending);
+
+        let request_path = &request.request_line.path;
+        let (content_type, body) = if prefers_json(request) {
+            ("application/json", directory_listing::json(request_path, &entries))
+        } else {
+            ("text/html; charset=utf-8", directory_listing::html(request_path, &entries))
+        };
+        let mut response = Response::builder()
+            .status_code(StatusCode::OK)
+            .header("Content-Type", content_type)
+            .header("Vary", "Accept");
+        if self.nosniff {
+            response = response.header("X-Content-Type-Options", "nosniff");
+        }
+        response.body(body.into_bytes()).build().unwrap_or_default()
+    }
+
+    // `file.gz` and the like next to path, the regular files within root
+    fn precompressed_siblings(&self, root: &Path, path: &Path) -> Vec<(ContentCoding, PathBuf)> {
+        self.precompressed
+            .iter()
+            .filter_map(|&coding| {
+                let mut sibling = path.as_os_str().to_owned();
+                sibling.push(".");
+                sibling.push(coding.extension());
+                let sibling = fs::canonicalize(sibling).ok()?;
+                (sibling.starts_with(root) && sibling.is_file()).then_some((coding, sibling))
+            })
+            .collect()
+    }
+
+    fn entity_tag(&self, path: &Path, file: &mut File, metadata: &Metadata) -> String {
+        if self.etag_source == EtagSource::Metadata {
+            return metadata_etag(metadata);
+        }
+
+        let (length, modified) = (metadata.len(), metadata.modified().ok());
+        let cached = self.lock_content_etags().get(path).and_then(|cached| {
+            (cached.length == length && cached.modified == modified).then(|| cached.etag.clone())
+        });
+        if let Some(etag) = cached {
+            return etag;
+        }
+
+        // Hashed without holding the lock, so other requests aren't held up
+        // by a large file. Two requests for a file not hashed yet may both
+        // hash it. The hash is read from the start, the file is served from
+        // there too.
+        let hash = content_hash(file).and_then(|hash| file.rewind().map(|_| hash));
+        match hash {
+            Ok(hash) => {
+                let etag = format!("\"{:x}-{:016x}\"", length, hash);
+                let cached = ContentEtag {
+                    length,
+                    modified,
+                    etag: etag.clone(),
+                };
+                self.lock_content_etags().insert(path.to_path_buf(), cached);
+                etag
+            }
+            Err(e) => {
+                error!("Error in hashing {}: {}", path.display(), e);
+                metadata_etag(metadata)
+            }
+        }
+    }
+
+    fn lock_content_etags(&self) -> MutexGuard<'_, HashMap<PathBuf, ContentEtag>> {
+        match self.content_etags.lock() {
+            Ok(content_etags) => content_etags,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    // Answers the preconditions of the request, then sends the whole file
+    // or the parts asked for with Range. An encoded file is always sent
+    // whole.
+    fn file_response(
+        &self,
+        request: &Request,
+        mut file: File,
+        metadata: &Metadata,
+        validators: &Validators,
+        representation: Representation,
+    ) -> Response {
+        let content_type = representation.content_type;
+        let length = metadata.len();
+
+        let mut response = Response::builder().header("ETag", &validators.etag);
+        if let Some(modified) = validators.last_modified {
+            response = response.header("Last-Modified", &format_http_date(modified));
+        }
+        match conditional::evaluate(request, validators) {
+            Precondition::Proceed => {}
+            Precondition::NotModified => {
+                let mut response = response
+                    .status_code(StatusCode::NotModified)
+                    .build()
+                    .unwrap_or_default();
+                if representation.coding.is_none() {
+                    self.compression
+                        .not_modified(request, &mut response, content_type, length);
+                }
+                return response;
+            }
+            Precondition::Failed => return status(StatusCode::PreconditionFailed),
+        }
+
+        response = response.header("Content-Type", content_type);
+        if self.nosniff {
+            response = response.header("X-Content-Type-Options", "nosniff");
+        }
+        if let Some(coding) = representation.coding {
+            return response
+                .header("Content-Encoding", coding.as_str())
+                .status_code(StatusCode::OK)
+                .sized_reader(file, length)
+                .build()
+                .unwrap_or_default();
+        }
+        response = response.header("Accept-Ranges", "bytes");
+
+        // A Range is only honoured if the client's copy is still current
+        let if_range = get_header(&request.headers, "If-Range");
+        let ranges = get_header(&request.headers, "Range")
+            .filter(|_| {
+                if_range.is_none_or(|validator| conditional::if_range_matches(validator, validators))
+            })
+            .and_then(|header| range::satisfiable_ranges(header, length));
+
+        let response = match ranges.as_deref() {
+            None => response.status_code(StatusCode::OK).sized_reader(file, length),
+            Some([]) => {
+                return Response::builder()
+                    .status_code(StatusCode::RangeNotSatisfiable)
+                    .header("Content-Range", &format!("bytes */{}", length))
+                    .build()
+                    .unwrap_or_default()
+            }
+            Some([range]) => {
+                if let Err(e) = file.seek(SeekFrom::Start(range.first)) {
+                    error!("Error in serving static file: {}", e);
+                    return status(StatusCode::InternalServerError);
+                }
+                response
+                    .status_code(StatusCode::PartialContent)
+                    .header("Content-Range", &range.content_range(length))
+                    .sized_reader(file.take(range.length()), range.length())
+            }
+            Some(ranges) => multipart_response(response, file, ranges, length, content_type),
+        };
+        response.build().unwrap_or_default()
+    }
+}
+
+// JSON only when the client ranks it above HTML. A missing or unparsable
+// Accept gets HTML.
+fn prefers_json(request: &Request) -> bool {
+    let accept = match get_header(&request.headers, "Accept").map(std::str::from_utf8) {
+        Some(Ok(accept)) => accept,
+        _ => return false,
+    };
+    let quality = |wanted: &str| {
+        let (kind, _) = wanted.split_once('/').unwrap_or_default();
+        http_utils::quality_values(accept)
+            .into_iter()
+            .filter_map(|(range, quality)| {
+                let specificity = if range.eq_ignore_ascii_case(wanted) {
+                    2
+                } else if range.eq_ignore_ascii_case(&format!("{}/*", kind)) {
+                    1
+                } else if range == "*/*" {
+                    0
+                } else {
+                    return None;
+                };
+                Some((specificity, quality))
+            })
+            // The most specific range decides
+            .max_by_key(|&(specificity, _)| specificity)
+            .map_or(0.0, |(_, quality)| quality)
+    };
+    let json = quality("application/json");
+    json > 0.0 && json > quality("text/html")
+}
+
+// What a file is sent as
+struct Representation<'a> {
+    content_type: &'a str,
+    // Content-Encoding of a precompressed file
+    coding: Option<ContentCoding>,
+}
+
+fn multipart_response(
+    response: ResponseBuilder,
+    file: File,
+    ranges: &[ByteRange],
+    length: u64,
+    content_type: &str,
+) -> ResponseBuilder {
+    let boundary = range::boundary();
+    let body = MultipartRanges::new(file, ranges, length, content_type, &boundary);
+    let body_length = body.length();
+    response
+        .status_code(StatusCode::PartialContent)
+        .header(
+            "Content-Type",
+            &format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .sized_reader(body, body_length)
+}
+
+// Changes whenever the size or the modification time does
+fn metadata_etag(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos());
+    format!("\"{:x}-{:x}\"", metadata.len(), modified)
+}
+
+// 64-bit FNV-1a of the rest of the file
+fn content_hash(file: &mut File) -> io::Result<u64> {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = match file.read(&mut buf) {
+            Ok(0) => return Ok(hash),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for &byte in &buf[..read] {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+fn status(status_code: StatusCode) -> Response {
+    Response::builder()
+        .status_code(status_code)
+        .build()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::{
+        super::{
+            http_utils::HttpProtocol,
+            request::{Headers, HttpMethod, RequestLine},
+        },
+        *,
+    };
+
+    const CONTENT: &[u8] = b"0123456789";
+    // Its seconds since the epoch overflow
+    const OVERSIZED_DATE: &str = "Sun, 06 Nov 300000000000 08:49:37 GMT";
+
+    // A directory of its own per test, the tests run in parallel
+    fn static_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("web-server-{}-{}", process::id(), name));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("file.txt"), CONTENT).unwrap();
+        root
+    }
+
+    fn get(root: &Path, headers: &[(&str, &str)]) -> Response {
+        let config = StaticFilesConfig {
+            root: root.to_path_buf(),
+            ..StaticFilesConfig::default()
+        };
+        let static_files = StaticFiles::new(&config, &CompressionConfig::default());
+        let headers: Headers = headers
+            .iter()
+            .map(|(name, value)| (name.as_bytes(), value.as_bytes()))
+            .collect();
+        let request_line = RequestLine {
+            method: HttpMethod::GET,
+            path: String::from("/file.txt"),
+            query: None,
+            protocol: HttpProtocol::HTTP1_1,
+        };
+        static_files.serve(&Request::new(request_line, headers, b"", Headers::new()))
+    }
+
+    #[test]
+    fn oversized_dates_are_ignored() {
+        let root = static_root("oversized-dates");
+        let cases: [&[(&str, &str)]; 3] = [
+            &[("If-Modified-Since", OVERSIZED_DATE)],
+            &[("If-Unmodified-Since", OVERSIZED_DATE)],
+            &[("Range", "bytes=0-1"), ("If-Range", OVERSIZED_DATE)],
+        ];
+        for headers in cases {
+            let response = get(&root, headers);
+            assert!(matches!(response.status_code(), StatusCode::OK), "{:?}", headers);
+            // The whole file, not a range of it
+            assert_eq!(response.body_length(), Some(CONTENT.len() as u64), "{:?}", headers);
+        }
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+
+use super::config::{TlsCertificate, TlsConfig};
+
+// Only HTTP/1.x is spoken, a client insisting on h2 fails the handshake
+const ALPN_PROTOCOLS: [&[u8]; 2] = [b"http/1.1", b"http/1.0"];
+
+// Picks the certificate by the SNI name of the client. Clients without SNI,
+// or asking for an unknown name, get the default certificate.
+#[derive(Debug)]
+struct SniResolver {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn find(&self, name: &str) -> Option<&Arc<CertifiedKey>> {
+        let name = name.to_ascii_lowercase();
+        self.by_name.get(&name).or_else(|| {
+            // A wildcard covers exactly one label
+            let (_, parent) = name.split_once('.')?;
+            self.by_name.get(&format!("*.{}", parent))
+        })
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.find(name))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+// Builds the rustls config of one listener from its certificate files
+pub fn server_config(config: &TlsConfig) -> Result<Arc<ServerConfig>, String> {
+    let provider = Arc::new(ring::default_provider());
+
+    let mut by_name = HashMap::new();
+    let mut unnamed = None;
+    let mut first = None;
+    for certificate in &config.certificates {
+        let key = Arc::new(load_certified_key(certificate, &provider)?);
+        for name in &certificate.server_names {
+            by_name.insert(name.to_ascii_lowercase(), Arc::clone(&key));
+        }
+        if certificate.server_names.is_empty() && unnamed.is_none() {
+            unnamed = Some(Arc::clone(&key));
+        }
+        first.get_or_insert(key);
+    }
+
+    // The first certificate without names, or else the first one
+    let default = unnamed.or(first);
+    if default.is_none() {
+        return Err(String::from("tls.certificates needs at least one entry"));
+    }
+    let resolver = SniResolver { by_name, default };
+
+    let mut server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    server_config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
+    Ok(Arc::new(server_config))
+}
+
+fn load_certified_key(
+    certificate: &TlsCertificate,
+    provider: &CryptoProvider,
+) -> Result<CertifiedKey, String> {
+    let cert_chain = load_cert_chain(&certificate.cert_chain)?;
+    let private_key = PrivateKeyDer::from_pem_file(&certificate.private_key)
+        .map_err(|e| format!("{}: {}", certificate.private_key.display(), e))?;
+    let signing_key = provider
+        .key_provider
+        .load_private_key(private_key)
+        .map_err(|e| format!("{}: {}", certificate.private_key.display(), e))?;
+
+    let key = CertifiedKey::new(cert_chain, signing_key);
+    key.keys_match()
+        .map_err(|e| format!("{}: {}", certificate.private_key.display(), e))?;
+    Ok(key)
+}
+
+fn load_cert_chain(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let cert_chain = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if cert_chain.is_empty() {
+        return Err(format!("{}: no certificate found", path.display()));
+    }
+    Ok(cert_chain)
+}